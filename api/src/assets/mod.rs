@@ -2,7 +2,7 @@ use std::collections::HashMap;
 
 use actix_web::web::{Json, Query, UrlEncoded};
 use actix_web::web::{Data, Path};
-use actix_web::{delete, get, post, put, HttpResponse, HttpRequest};
+use actix_web::{delete, get, patch, post, put, HttpResponse};
 use mongodb::bson::{doc, oid::ObjectId};
 use serde::{Deserialize, Serialize};
 use serde_json::{json, Value};
@@ -10,6 +10,9 @@ use uuid::Uuid;
 use xpertly_common::Device;
 use xpertly_common::{asset::Asset, asset::AssetTag, Display};
 
+use crate::error::ApiError;
+use crate::filter::{escape_regex, Condition, Filter};
+use crate::query::{one_or_many, QueryString};
 use crate::WebServerData;
 
 #[post("/api/tenants/{tenant_id}/integrations/{integration_type}/{integration_id}/assets/create")]
@@ -17,14 +20,23 @@ pub async fn create_asset(
     ws_data: Data<WebServerData>,
     path: Path<(String, String, String)>,
     data: Json<Value>,
-) -> HttpResponse {
+) -> Result<HttpResponse, ApiError> {
     let (tenant_id, integration_type, integration_id) = path.into_inner();
     if let Some(db) = &ws_data.db {
         // let asset_id = format!("asset#{}#{}#{}", integration_type, integration_id, Uuid::new_v4());
         let asset_id = Uuid::new_v4().to_string();
-        let asset_type = data["type"].as_str().unwrap().to_string();
-        let vendor_identifier = data["vendorIdentifier"].as_str().unwrap().to_string();
-        let attributes = data.get("attributes").unwrap().clone();
+        let asset_type = match data["type"].as_str() {
+            Some(asset_type) => asset_type.to_string(),
+            None => return Err(ApiError::BadRequest(String::from("type must be a string"))),
+        };
+        let vendor_identifier = match data["vendorIdentifier"].as_str() {
+            Some(vendor_identifier) => vendor_identifier.to_string(),
+            None => return Err(ApiError::BadRequest(String::from("vendorIdentifier must be a string"))),
+        };
+        let attributes = match data.get("attributes") {
+            Some(attributes) => attributes.clone(),
+            None => return Err(ApiError::BadRequest(String::from("attributes are required"))),
+        };
         let data = Asset {
             id: None,
             tenant_id,
@@ -36,13 +48,10 @@ pub async fn create_asset(
             attributes,
         };
 
-        let asset_detail = db.insert_one(&data).await;
-        match asset_detail {
-            Ok(_) => HttpResponse::Ok().json(data),
-            Err(err) => HttpResponse::InternalServerError().body(err.to_string()),
-        }
+        db.insert_one(&data).await?;
+        Ok(HttpResponse::Ok().json(data))
     } else {
-        HttpResponse::InternalServerError().body("No database connection")
+        Err(ApiError::NoDatabase)
     }
 }
 
@@ -193,16 +202,81 @@ pub async fn create_device_tag(
     }
 }
 
+// recursively merges `patch` into `target`. Nested objects are merged key by key, anything else is replaced
+fn merge_attributes(target: &mut Value, patch: &Value) {
+    match (target, patch) {
+        (Value::Object(target), Value::Object(patch)) => {
+            for (key, value) in patch {
+                merge_attributes(target.entry(key.clone()).or_insert(Value::Null), value);
+            }
+        }
+        (target, patch) => *target = patch.clone(),
+    }
+}
+
+#[patch("/api/tenants/{tenant_id}/integrations/{integration_type}/{integration_id}/assets/{asset_id}")]
+pub async fn update_asset(
+    ws_data: Data<WebServerData>,
+    path: Path<(String, String, String, String)>,
+    data: Json<Value>,
+) -> HttpResponse {
+    let (tenant_id, integration_type, integration_id, asset_id) = path.into_inner();
+    if let Some(db) = &ws_data.db {
+        let attributes = match data.get("attributes") {
+            Some(attributes) if attributes.is_object() => attributes,
+            _ => return HttpResponse::BadRequest().body("attributes must be an object"),
+        };
+
+        let sk = format!(
+            "asset#{}#{}#{}",
+            integration_type,
+            integration_id,
+            asset_id
+        );
+        let filter = doc! {"SK": sk, "PK": tenant_id};
+
+        let asset: Option<Asset> = db.filter_item(Some(filter)).await.unwrap();
+
+        if let Some(mut val) = asset {
+            merge_attributes(&mut val.attributes, attributes);
+
+            match db.update_item::<Asset>(&val.id.unwrap().to_string(), val.clone()).await {
+                Ok(_) => HttpResponse::Ok().json(val.display()),
+                Err(err) => HttpResponse::InternalServerError().body(err.to_string()),
+            }
+        } else {
+            HttpResponse::NotFound().body("Asset not found")
+        }
+    } else {
+        HttpResponse::InternalServerError().body("No database connection")
+    }
+}
+
 #[get("/api/tenants/{tenant_id}/integrations/{integration_type}/{integration_id}/assets")]
 pub async fn get_assets(
     ws_data: Data<WebServerData>,
     path: Path<(String, String, String)>,
+    params: Query<HashMap<String, String>>,
 ) -> HttpResponse {
     let (tenant_id, integration_type, integration_id) = path.into_inner();
+    // query params of the form attributes.<path>=<value> only return items whose attribute equals the value
+    let attributes = params
+        .iter()
+        .filter_map(|(key, value)| {
+            let path = key.strip_prefix("attributes.")?;
+            Some((path.to_string(), Condition::Eq(value.clone().into())))
+        })
+        .collect::<HashMap<String, Condition>>();
     if let Some(db) = &ws_data.db {
-        let asset_filter = doc! {"PK": tenant_id.clone(), "SK": {"$regex": format!("^asset#{}#{}#", integration_type, integration_id)}};
+        let asset_filter = Filter::tenant(&tenant_id)
+            .integration("asset", &integration_type, &integration_id)
+            .attributes(attributes.clone())
+            .build();
         let assets: Vec<Asset> = db.filter_items(Some(asset_filter)).await.unwrap();
-        let device_filter = doc! {"PK": tenant_id.clone(), "SK": {"$regex": format!("^device#{}#{}#", integration_type, integration_id)}};
+        let device_filter = Filter::tenant(&tenant_id)
+            .integration("device", &integration_type, &integration_id)
+            .attributes(attributes)
+            .build();
         let devices: Vec<Device> = db.filter_items(Some(device_filter)).await.unwrap();
         HttpResponse::Ok().json(json!({"assets": assets.display(), "devices": devices.display()}))
     } else {
@@ -210,53 +284,103 @@ pub async fn get_assets(
     }
 }
 
+#[derive(Deserialize, Debug)]
+pub struct TagParams {
+    // only return tags starting with this prefix
+    q: Option<String>,
+    limit: Option<i64>,
+    // number of tags to skip, the `nextOffset` of the previous page
+    offset: Option<u64>,
+}
+
 #[get("/api/tenants/{tenant_id}/get-all-tags")]
-pub async fn get_all_tags(ws_data: Data<WebServerData>, path: Path<String>) -> HttpResponse {
+pub async fn get_all_tags(
+    ws_data: Data<WebServerData>,
+    path: Path<String>,
+    params: Query<TagParams>,
+) -> HttpResponse {
     let tenant_id = path.into_inner();
     if let Some(db) = &ws_data.db {
-        let filter = doc! {"SK1": tenant_id};
-        let asset_tags: Vec<AssetTag> = db.filter_items(Some(filter)).await.unwrap();
-        let mut only_tag_words: Vec<String> = vec![];
-        asset_tags.into_iter().for_each(|tag| {
-            if !only_tag_words.contains(&tag.tag) {
-                only_tag_words.push(tag.tag);
+        // tags are stored in the SK as tag#<tag>
+        let prefix = format!("^tag#{}", escape_regex(params.q.as_deref().unwrap_or("")));
+        let offset = params.offset.unwrap_or(0);
+        let mut pipeline = vec![
+            doc! {"$match": {"SK1": tenant_id, "SK": {"$regex": prefix}}},
+            doc! {"$group": {"_id": "$SK"}},
+            doc! {"$sort": {"_id": 1}},
+            doc! {"$skip": offset as i64},
+        ];
+        // one more than the limit, to tell whether there's another page
+        if let Some(limit) = params.limit {
+            pipeline.push(doc! {"$limit": limit.max(0) + 1});
+        }
+        let groups = match db.aggregate::<AssetTag>(pipeline).await {
+            Ok(groups) => groups,
+            Err(err) => return HttpResponse::InternalServerError().body(err.to_string()),
+        };
+        let mut tags = groups
+            .iter()
+            .filter_map(|group| group.get_str("_id").ok())
+            .filter_map(|sk| sk.strip_prefix("tag#"))
+            .map(|tag| tag.to_string())
+            .collect::<Vec<String>>();
+        let mut next_offset = None;
+        if let Some(limit) = params.limit {
+            let limit = limit.max(0) as usize;
+            if tags.len() > limit {
+                tags.truncate(limit);
+                next_offset = Some(offset + limit as u64);
             }
-        });
-        HttpResponse::Ok().json(json!({"tags": only_tag_words}))
+        }
+        HttpResponse::Ok().json(json!({"tags": tags, "nextOffset": next_offset}))
     } else {
         HttpResponse::InternalServerError().body("No database connection")
     }
 }
 
 #[derive(Deserialize, Serialize, Debug, Clone)]
-struct AssetByTagParams {
-    tags: Vec<String>
+pub struct AssetByTagParams {
+    // given as repeated params, ?tags=a&tags=b
+    #[serde(deserialize_with = "one_or_many")]
+    tags: Vec<String>,
+    #[serde(rename = "match")]
+    match_mode: Option<String>,
 }
 
 #[get("/api/tenants/{tenant_id}/assets-by-tags")]
 pub async fn get_assets_by_tags(
     ws_data: Data<WebServerData>,
     path: Path<String>,
-    req: HttpRequest,
+    params: QueryString<AssetByTagParams>,
 ) -> HttpResponse {
     let tenant_id = path.into_inner();
-    let mut query_params: HashMap<String, Vec<String>> = HashMap::new();
-    req.query_string().split("&").into_iter().for_each(|param| {
-        let split: Vec<&str> = param.split("=").collect();
-        query_params
-            .entry(split[0].to_string())
-            .or_insert(vec![])
-            .push(urlencoding::decode(split[1]).unwrap().into_owned());
-    });
-
-    let tags = query_params.get("tags").unwrap();
+    let tags = &params.tags;
+    // `any` (default) buckets assets under each tag they match, `all` returns a single list of the assets that have every tag
+    let match_all = match params.match_mode.as_deref() {
+        None | Some("any") => false,
+        Some("all") => true,
+        Some(other) => {
+            return HttpResponse::BadRequest().body(format!("Invalid match parameter: {}, expected 'all' or 'any'", other))
+        }
+    };
 
     let mut ret = HashMap::new();
     if let Some(db) = &ws_data.db {
+        if match_all {
+            let asset_filter = Filter::tenant(&tenant_id).all("attributes.assetTags", tags.clone()).build();
+            let assets: Vec<Asset> = db.filter_items(Some(asset_filter)).await.unwrap();
+            let device_filter = Filter::tenant(&tenant_id).all("attributes.deviceTags", tags.clone()).build();
+            let devices: Vec<Device> = db.filter_items(Some(device_filter)).await.unwrap();
+            return HttpResponse::Ok().json(json!({
+                "assets": assets.display(),
+                "devices": devices.display(),
+            }));
+        }
+
         for tag in tags {
-            let asset_filter = doc!{"attributes.assetTags": tag.clone(), "PK": tenant_id.clone()};
+            let asset_filter = Filter::tenant(&tenant_id).eq("attributes.assetTags", tag.clone()).build();
             let assets: Vec<Asset> = db.filter_items(Some(asset_filter)).await.unwrap();
-            let device_filter = doc!{"attributes.deviceTags": tag.clone(), "PK": tenant_id.clone()};
+            let device_filter = Filter::tenant(&tenant_id).eq("attributes.deviceTags", tag.clone()).build();
             let devices: Vec<Device> = db.filter_items(Some(device_filter)).await.unwrap();
             ret.entry("assets")
                 .or_insert(HashMap::new())
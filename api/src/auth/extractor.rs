@@ -2,6 +2,7 @@ use super::middleware::{ XpertlyAuth };
 use actix_web::{FromRequest, HttpMessage, HttpRequest};
 use super::error::Error;
 use xpertly_common::auth::{ Auth, BearerToken, Claims };
+use tracing::debug;
 
 use std::{
     future::{ready, Ready},
@@ -16,7 +17,7 @@ impl FromRequest for Authenticated {
 
     fn from_request(req: &HttpRequest, payload: &mut actix_web::dev::Payload) -> Self::Future {
         if let Some(error) = req.extensions().get::<Box<dyn std::error::Error>>() {
-            dbg!(error);
+            debug!(%error, "authentication failed");
             return ready(Err(Error::AuthenticationError))
         }
 
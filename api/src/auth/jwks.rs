@@ -0,0 +1,62 @@
+use super::middleware::XpertlyJwk;
+use std::error::Error;
+use std::sync::{Arc, RwLock};
+use std::time::Duration;
+use tracing::{debug, warn};
+
+const JWKS_URL: &str =
+    "https://cognito-idp.ap-southeast-2.amazonaws.com/ap-southeast-2_rf7hpngbY/.well-known/jwks.json";
+const FETCH_ATTEMPTS: u32 = 5;
+// doubled after every failed attempt
+const INITIAL_BACKOFF: Duration = Duration::from_millis(500);
+const REFRESH_INTERVAL: Duration = Duration::from_secs(60 * 60);
+
+// keys used to verify Cognito tokens, shared with the authentication middleware and replaced on refresh
+pub type KeyStore = Arc<RwLock<Vec<XpertlyJwk>>>;
+
+async fn fetch_jwks(client: &reqwest::Client) -> Result<Vec<XpertlyJwk>, Box<dyn Error>> {
+    let jwks: serde_json::Value = client
+        .get(JWKS_URL)
+        .send()
+        .await?
+        .error_for_status()?
+        .json()
+        .await?;
+    debug!(?jwks, "fetched jwks");
+    Ok(serde_json::from_value::<Vec<XpertlyJwk>>(jwks["keys"].clone())?)
+}
+
+// retries with exponential backoff so the endpoint being briefly unavailable doesn't stop the server starting
+pub async fn fetch_jwks_with_retry(client: &reqwest::Client) -> Result<Vec<XpertlyJwk>, Box<dyn Error>> {
+    let mut backoff = INITIAL_BACKOFF;
+    let mut attempt = 1;
+    loop {
+        match fetch_jwks(client).await {
+            Ok(jwks) => return Ok(jwks),
+            Err(err) if attempt < FETCH_ATTEMPTS => {
+                warn!(%err, attempt, attempts = FETCH_ATTEMPTS, ?backoff, "failed to fetch JWKS, retrying");
+                tokio::time::sleep(backoff).await;
+                backoff *= 2;
+                attempt += 1;
+            }
+            Err(err) => return Err(err),
+        }
+    }
+}
+
+// refetches the keys every REFRESH_INTERVAL so rotated keys are picked up without a restart.
+// The current keys are kept if a refresh fails
+pub fn refresh_periodically(client: reqwest::Client, key_store: KeyStore) {
+    actix_web::rt::spawn(async move {
+        let mut interval = tokio::time::interval(REFRESH_INTERVAL);
+        // the first tick completes immediately and the keys have only just been fetched
+        interval.tick().await;
+        loop {
+            interval.tick().await;
+            match fetch_jwks_with_retry(&client).await {
+                Ok(jwks) => *key_store.write().unwrap() = jwks,
+                Err(err) => warn!(%err, "failed to refresh JWKS, keeping the current keys"),
+            }
+        }
+    });
+}
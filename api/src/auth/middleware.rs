@@ -12,7 +12,9 @@ use futures::{
     FutureExt
 };
 use std::str::FromStr;
+use tracing::debug;
 use super::error::Error as AuthorizationError;
+use super::jwks::KeyStore;
 use jsonwebtoken::{decode, decode_header, DecodingKey, Validation, Algorithm, TokenData};
 
 pub type XpertlyAuth = Rc<TokenData<Claims>>;
@@ -29,12 +31,12 @@ pub struct XpertlyJwk {
 }
 
 pub struct AuthenticateMiddlewareFactory {
-    key_store: Rc<Vec<XpertlyJwk>>
+    key_store: KeyStore
 }
 
 impl AuthenticateMiddlewareFactory {
-    pub fn new(key_store: Vec<XpertlyJwk>) -> Self {
-        AuthenticateMiddlewareFactory { key_store: Rc::new(key_store) }
+    pub fn new(key_store: KeyStore) -> Self {
+        AuthenticateMiddlewareFactory { key_store }
     }
 }
 
@@ -57,7 +59,7 @@ where
 }
 
 pub struct AuthenticateMiddleware<S> {
-    key_store: Rc<Vec<XpertlyJwk>>,
+    key_store: KeyStore,
     service: Rc<S>
 }
 
@@ -72,28 +74,29 @@ where
 
     fn call(&self, req: ServiceRequest) -> Self::Future {
         let srv = Rc::clone(&self.service);
-        let key_store = self.key_store.clone();
+        // copying the current keys so the lock isn't held while the request is handled
+        let key_store = self.key_store.read().unwrap().clone();
 
         async move {
             // if token exists
             if let Some(auth) = req.headers().get("Authorization") {
-                dbg!("header present");
+                debug!("authorization header present");
                 // if token is a valid str
                 if let Ok(token) = auth.to_str() {
-                    dbg!("valid str");
+                    debug!("authorization header is a valid string");
                     let token = BearerToken::from_str(token).map_err(|_| AuthorizationError::AuthenticationError)?;
                     if let Ok(header) = decode_header(&token) {
-                        dbg!("valid header");
+                        debug!("token header decoded");
                         // look for matching key in the jwks
                         key_store.iter().for_each(|k| {
                             if &k.kid == header.kid.as_ref().unwrap() {
-                                dbg!(&k.kid);
+                                debug!(kid = %k.kid, "matched jwk");
                                 // if token can be successfully decoded
                                 match decode::<Claims>(token.as_str(), 
                                     &DecodingKey::from_rsa_components(&k.n, &k.e).unwrap(), 
                                     &Validation::new(Algorithm::RS256)) {
                                     Ok(claims) => {
-                                        dbg!(&claims);
+                                        debug!(?claims, "token verified");
                                         req.extensions_mut().insert::<XpertlyAuth>(Rc::new(claims));
                                         req.extensions_mut().insert::<BearerToken>(token.clone());
                                     },
@@ -1,3 +1,4 @@
 pub mod error;
 pub mod extractor;
+pub mod jwks;
 pub mod middleware;
\ No newline at end of file
@@ -0,0 +1,79 @@
+use actix_web::{body::BoxBody, http::StatusCode, HttpResponse};
+use serde_json::json;
+use thiserror::Error;
+
+/// Errors returned by the API's handlers, rendered as a JSON body of the form `{"message": "..."}`
+#[derive(Debug, Error)]
+pub enum ApiError {
+    #[error("{0}")]
+    BadRequest(String),
+
+    #[error("{0}")]
+    NotFound(String),
+
+    #[error("{0}")]
+    Conflict(String),
+
+    // the caller's token was rejected, either by us or by a service we called on their behalf
+    #[error("{0}")]
+    Unauthorized(String),
+
+    // another service responded, but not with something we could use
+    #[error("{0}")]
+    BadGateway(String),
+
+    #[error("Invalid token: {0}")]
+    InvalidToken(#[from] jsonwebtoken::errors::Error),
+
+    // a request to another service (Avicenna, Elastic) failed or returned an error status
+    #[error("Upstream request failed: {0}")]
+    Upstream(#[from] reqwest::Error),
+
+    // data from another service or the database didn't have the expected shape
+    #[error("Unexpected response: {0}")]
+    Deserialization(#[from] serde_json::Error),
+
+    #[error("Database error: {0}")]
+    Database(#[from] mongodb::bson::extjson::de::Error),
+
+    // a write to the database failed
+    #[error("Database error: {0}")]
+    DatabaseWrite(#[from] mongodb::error::Error),
+
+    #[error("No database connection")]
+    NoDatabase,
+
+    // the request is fine but a limit on how much the caller can do at once was reached
+    #[error("{0}")]
+    TooManyRequests(String),
+
+    // a part of this service the request needs isn't running
+    #[error("{0}")]
+    Unavailable(String),
+
+    #[error("{0}")]
+    Internal(String),
+}
+
+impl actix_web::error::ResponseError for ApiError {
+    fn error_response(&self) -> HttpResponse<BoxBody> {
+        HttpResponse::build(self.status_code()).json(json!({ "message": self.to_string() }))
+    }
+
+    fn status_code(&self) -> StatusCode {
+        match self {
+            ApiError::BadRequest(_) => StatusCode::BAD_REQUEST,
+            ApiError::NotFound(_) => StatusCode::NOT_FOUND,
+            ApiError::Conflict(_) => StatusCode::CONFLICT,
+            ApiError::TooManyRequests(_) => StatusCode::TOO_MANY_REQUESTS,
+            ApiError::Unavailable(_) => StatusCode::SERVICE_UNAVAILABLE,
+            ApiError::InvalidToken(_) | ApiError::Unauthorized(_) => StatusCode::UNAUTHORIZED,
+            ApiError::Upstream(_) | ApiError::Deserialization(_) | ApiError::BadGateway(_) => {
+                StatusCode::BAD_GATEWAY
+            }
+            ApiError::Database(_) | ApiError::DatabaseWrite(_) | ApiError::NoDatabase | ApiError::Internal(_) => {
+                StatusCode::INTERNAL_SERVER_ERROR
+            }
+        }
+    }
+}
@@ -0,0 +1,98 @@
+use std::collections::HashMap;
+
+use mongodb::bson::{Bson, Document};
+
+/// A condition on a single field, translated into the matching Mongo query operator
+#[derive(Debug, Clone, PartialEq)]
+pub enum Condition {
+    Eq(Bson),
+    // the array field contains every one of the values
+    All(Vec<Bson>),
+    // the string field starts with the value, matched literally
+    Prefix(String),
+}
+
+impl Condition {
+    fn operator(&self) -> (&'static str, Bson) {
+        match self {
+            Condition::Eq(value) => ("$eq", value.clone()),
+            Condition::All(values) => ("$all", Bson::Array(values.clone())),
+            Condition::Prefix(prefix) => ("$regex", Bson::String(format!("^{}", escape_regex(prefix)))),
+        }
+    }
+}
+
+/// Builds a Mongo filter out of field path -> condition pairs so tenant, integration, tag and attribute
+/// filters can be combined into a single query. Conditions on the same path are merged into one
+/// operator document, a later condition with the same operator replaces the earlier one.
+#[derive(Debug, Clone, Default)]
+pub struct Filter {
+    conditions: Vec<(String, Condition)>,
+}
+
+impl Filter {
+    /// Starts a filter scoped to a tenant's items
+    pub fn tenant(tenant_id: &str) -> Self {
+        Filter::default().eq("PK", tenant_id)
+    }
+
+    pub fn condition(mut self, path: &str, condition: Condition) -> Self {
+        self.conditions.push((path.to_string(), condition));
+        self
+    }
+
+    pub fn eq(self, path: &str, value: impl Into<Bson>) -> Self {
+        self.condition(path, Condition::Eq(value.into()))
+    }
+
+    pub fn all<T: Into<Bson>>(self, path: &str, values: impl IntoIterator<Item = T>) -> Self {
+        self.condition(path, Condition::All(values.into_iter().map(Into::into).collect()))
+    }
+
+    pub fn prefix(self, path: &str, prefix: &str) -> Self {
+        self.condition(path, Condition::Prefix(prefix.to_string()))
+    }
+
+    /// Only matches items belonging to an integration, `kind` is the SK prefix e.g. asset or device
+    pub fn integration(self, kind: &str, integration_type: &str, integration_id: &str) -> Self {
+        self.prefix("SK", &format!("{}#{}#{}#", kind, integration_type, integration_id))
+    }
+
+    /// Adds a condition for each entry, paths are relative to the item's `attributes`
+    /// e.g. `deviceTags` or `network.name`
+    pub fn attributes(self, attributes: HashMap<String, Condition>) -> Self {
+        attributes.into_iter().fold(self, |filter, (path, condition)| {
+            filter.condition(&format!("attributes.{}", path), condition)
+        })
+    }
+
+    pub fn build(self) -> Document {
+        let mut filter = Document::new();
+        for (path, condition) in self.conditions {
+            let (operator, value) = condition.operator();
+            match filter.get_mut(&path) {
+                Some(Bson::Document(operators)) => {
+                    operators.insert(operator, value);
+                }
+                _ => {
+                    let mut operators = Document::new();
+                    operators.insert(operator, value);
+                    filter.insert(path, operators);
+                }
+            }
+        }
+        filter
+    }
+}
+
+// escapes a user supplied string so it's matched literally inside a Mongo $regex
+pub fn escape_regex(value: &str) -> String {
+    let mut escaped = String::with_capacity(value.len());
+    for c in value.chars() {
+        if "\\^$.|?*+()[]{}".contains(c) {
+            escaped.push('\\');
+        }
+        escaped.push(c);
+    }
+    escaped
+}
@@ -0,0 +1,125 @@
+// Logs of past executions, read back from the tenant's worker run index the worker's `ElasticLogSink` writes to.
+// Live logs only go over the websocket and SSE streams, this gives the same logs once a run has finished
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use uuid::Uuid;
+
+use crate::error::ApiError;
+
+const DEFAULT_LIMIT: usize = 100;
+// Elasticsearch won't page past 10,000 hits with from/size, so neither do we
+const MAX_LIMIT: usize = 1000;
+const MAX_WINDOW: usize = 10_000;
+
+#[derive(Deserialize, Debug, Default)]
+pub struct HistoryParams {
+    offset: Option<usize>,
+    // at most 1000, defaults to 100
+    limit: Option<usize>,
+}
+
+/// A page of an execution's logs, oldest first
+#[derive(Serialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct LogHistory {
+    // the logs as they were written. Older logs may predate fields `WorkerLog` has since gained, so they're passed
+    // on without being deserialized
+    pub logs: Vec<Value>,
+    pub total: u64,
+    pub offset: usize,
+    pub limit: usize,
+}
+
+impl HistoryParams {
+    fn page(&self) -> Result<(usize, usize), ApiError> {
+        let offset = self.offset.unwrap_or(0);
+        let limit = self.limit.unwrap_or(DEFAULT_LIMIT).clamp(1, MAX_LIMIT);
+        if offset + limit > MAX_WINDOW {
+            return Err(ApiError::BadRequest(format!(
+                "offset + limit can't be more than {}",
+                MAX_WINDOW
+            )));
+        }
+        Ok((offset, limit))
+    }
+}
+
+// matches the execution's logs whether its ID is mapped as a keyword or as text
+fn search_body(execution_id: Uuid, offset: usize, limit: usize) -> Value {
+    json!({
+        "query": { "match_phrase": { "executionId": execution_id.to_string() } },
+        "sort": [{ "@timestamp": { "order": "asc" } }],
+        "from": offset,
+        "size": limit,
+    })
+}
+
+/// Fetches a page of the execution's logs from the Elasticsearch cluster at ELASTICSEARCH_URL, authenticating with
+/// ELASTICSEARCH_API_KEY if it's set. An index that doesn't exist yet has no logs
+pub async fn fetch_history(
+    tenant_id: &str,
+    execution_id: Uuid,
+    params: &HistoryParams,
+) -> Result<LogHistory, ApiError> {
+    let (offset, limit) = params.page()?;
+    let url = std::env::var("ELASTICSEARCH_URL")
+        .map_err(|_| ApiError::Internal(String::from("ELASTICSEARCH_URL isn't set, log history is unavailable")))?;
+
+    let mut request = xpertly_worker::client::build_client()
+        .post(format!(
+            "{}/xpertly_worker_run_{}/_search",
+            url.trim_end_matches('/'),
+            tenant_id
+        ))
+        .json(&search_body(execution_id, offset, limit));
+    if let Ok(api_key) = std::env::var("ELASTICSEARCH_API_KEY") {
+        request = request.header("Authorization", format!("ApiKey {}", api_key));
+    }
+    let response = request.send().await?;
+
+    let status = response.status();
+    if status == reqwest::StatusCode::NOT_FOUND {
+        return Ok(LogHistory { logs: vec![], total: 0, offset, limit });
+    }
+    if !status.is_success() {
+        return Err(ApiError::BadGateway(format!(
+            "Failed to search logs for execution {}: {}",
+            execution_id, status
+        )));
+    }
+
+    let results = response.json::<Value>().await?;
+    let logs = results["hits"]["hits"]
+        .as_array()
+        .map(|hits| hits.iter().map(|hit| hit["_source"].clone()).collect())
+        .unwrap_or_default();
+    Ok(LogHistory {
+        logs,
+        total: results["hits"]["total"]["value"].as_u64().unwrap_or(0),
+        offset,
+        limit,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_history_page() {
+        let params = HistoryParams::default();
+        assert_eq!(params.page().unwrap(), (0, DEFAULT_LIMIT));
+
+        let params = HistoryParams { offset: Some(200), limit: Some(5000) };
+        assert_eq!(params.page().unwrap(), (200, MAX_LIMIT));
+
+        let params = HistoryParams { offset: Some(9500), limit: Some(1000) };
+        assert!(matches!(params.page(), Err(ApiError::BadRequest(_))));
+
+        let execution_id = Uuid::new_v4();
+        let body = search_body(execution_id, 200, 50);
+        assert_eq!(body["query"]["match_phrase"]["executionId"], execution_id.to_string());
+        assert_eq!(body["sort"][0]["@timestamp"]["order"], "asc");
+        assert_eq!((body["from"].clone(), body["size"].clone()), (json!(200), json!(50)));
+    }
+}
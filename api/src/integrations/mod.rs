@@ -1,10 +1,11 @@
 use actix_web::web::Json;
 use actix_web::web::{Data, Path};
-use actix_web::{delete, get, post, put, HttpResponse};
+use actix_web::{delete, get, patch, post, put, HttpResponse};
 use mongodb::bson::{doc, oid::ObjectId};
 use serde_json::{json, Value, Map};
 use uuid::Uuid;
-use xpertly_common::{asset::Asset, asset::AssetTag, integration::Integration, Display};
+use xpertly_common::integration::{Integration, IntegrationError, SUPPORTED_VENDORS};
+use xpertly_common::{asset::Asset, asset::AssetTag, Display};
 
 use crate::WebServerData;
 
@@ -25,11 +26,24 @@ pub async fn create_integration(
             }
         );
 
-        for (key, value) in data.as_object().unwrap().iter() {
+        let fields = match data.as_object() {
+            Some(fields) => fields,
+            None => return HttpResponse::BadRequest().body("integration must be an object"),
+        };
+        for (key, value) in fields.iter() {
             integration_base[key] = value.clone();
         }
 
-        let integration = serde_json::from_value::<Integration>(integration_base).unwrap();
+        let integration = match Integration::from_value(integration_base) {
+            Ok(integration) => integration,
+            Err(err @ IntegrationError::UnknownVendor(_)) => {
+                return HttpResponse::BadRequest().json(json!({
+                    "message": err.to_string(),
+                    "supportedVendors": SUPPORTED_VENDORS,
+                }))
+            }
+            Err(err) => return HttpResponse::BadRequest().body(err.to_string()),
+        };
         let integration_detail = db.insert_one(&integration).await;
         match integration_detail {
             Ok(_) => HttpResponse::Ok().json(integration.display()),
@@ -79,4 +93,64 @@ pub async fn get_integration(
     } else {
         HttpResponse::InternalServerError().body("No database connection")
     }
-}
\ No newline at end of file
+}
+
+// applies an RFC 7386 JSON merge patch: objects are merged key by key, null removes a key and anything else
+// replaces the target
+fn merge_patch(target: &mut Value, patch: &Value) {
+    if let Value::Object(patch) = patch {
+        if !target.is_object() {
+            *target = Value::Object(Map::new());
+        }
+        let target = target.as_object_mut().unwrap();
+        for (key, value) in patch {
+            if value.is_null() {
+                target.remove(key);
+            } else {
+                merge_patch(target.entry(key.clone()).or_insert(Value::Null), value);
+            }
+        }
+    } else {
+        *target = patch.clone();
+    }
+}
+
+// partially updates an integration with a JSON merge patch, e.g. {"apiKey": "..."} to rotate a Meraki API key
+#[patch("/api/tenants/{tenant_id}/integrations/{integration_type}/{integration_id}")]
+pub async fn update_integration(
+    ws_data: Data<WebServerData>,
+    path: Path<(String, String, String)>,
+    data: Json<Value>,
+) -> HttpResponse {
+    let (tenant_id, integration_type, integration_id) = path.into_inner();
+    if let Some(db) = &ws_data.db {
+        if !data.is_object() {
+            return HttpResponse::BadRequest().body("patch must be an object");
+        }
+        // the keys identifying the integration can't be patched
+        if ["PK", "SK", "_id"].iter().any(|key| data.get(key).is_some()) {
+            return HttpResponse::BadRequest().body("PK, SK and _id can't be updated");
+        }
+
+        let filter = doc! {"PK": tenant_id, "SK": format!("integration#{}#{}", integration_type, integration_id)};
+        let integration = match db.filter_item::<Integration>(Some(filter)).await {
+            Ok(Some(integration)) => integration,
+            Ok(None) => return HttpResponse::NotFound().body("Integration not found"),
+            Err(err) => return HttpResponse::InternalServerError().body(err.to_string()),
+        };
+
+        let mut document = serde_json::to_value(&integration).unwrap();
+        merge_patch(&mut document, &data);
+        let updated = match serde_json::from_value::<Integration>(document) {
+            Ok(updated) => updated,
+            Err(err) => return HttpResponse::BadRequest().body(format!("Invalid integration: {}", err)),
+        };
+
+        match db.replace_item::<Integration>(&integration.id().unwrap().to_hex(), updated.clone()).await {
+            Ok(_) => HttpResponse::Ok().json(updated.display()),
+            Err(err) => HttpResponse::InternalServerError().body(err.to_string()),
+        }
+    } else {
+        HttpResponse::InternalServerError().body("No database connection")
+    }
+}
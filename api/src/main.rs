@@ -1,33 +1,39 @@
-use actix::{Actor, Addr, Recipient, StreamHandler};
+use actix::{Actor, Addr, AsyncContext, Recipient, StreamHandler};
 use actix_web::{
-    get, middleware::Logger, post, web::Data, web::Json, web::Path, web::Payload, App, HttpServer,
-    Responder,
+    get, middleware::Logger, post, web::Data, web::Json, web::Path, web::Payload, web::Query, App,
+    HttpServer,
 };
 use actix_web::{HttpRequest, HttpResponse};
 use actix_web_actors::ws;
 use env_logger;
 use jsonwebtoken::{decode, DecodingKey, Validation};
 use mongo_api::MongoDbClient;
+use mongodb::bson::doc;
 use reqwest::header::{HeaderName, HeaderValue};
 use serde::{Deserialize, Serialize};
 use serde_json::{json, Value};
+use std::collections::HashMap;
 use std::str::FromStr;
+use tracing::{debug, warn};
 use uuid::Uuid;
-use xpertly_common::{AvicennaUser, TestUser, WorkerConfig};
+use xpertly_common::{AvicennaUser, ExecutionRecord, TestUser, WorkerConfig, WorkerDefinition, ENABLED_VENDORS};
 
 mod auth;
 use auth::extractor::Authenticated;
-use auth::middleware::{AuthenticateMiddlewareFactory, XpertlyJwk};
+use auth::jwks::{fetch_jwks_with_retry, refresh_periodically, KeyStore};
+use auth::middleware::AuthenticateMiddlewareFactory;
 
 // use websockets::live_updates::LiveUpdateWsActor;
 use actix_ws::{handle, Message};
+use xpertly_worker::executions::Lookup;
 use xpertly_worker::WorkerLog;
 
 mod websockets;
 use dotenv::dotenv;
 use std::env;
-use websockets::server::LiveUpdateServer;
+use websockets::server::{LiveUpdateServer, Subscribe, SubscribeResult};
 use websockets::session::WsActor;
+use websockets::sse::{sse_event, SseActor};
 
 mod test_api;
 use test_api::{create_user, get_user, update_user};
@@ -38,6 +44,19 @@ use assets::*;
 mod integrations;
 use integrations::*;
 
+mod error;
+use error::ApiError;
+
+mod filter;
+
+mod history;
+use history::HistoryParams;
+
+mod query;
+
+mod workers;
+use workers::*;
+
 type ClientSocket = Recipient<WorkerLog>;
 #[derive(Clone)]
 pub struct WebServerData {
@@ -57,11 +76,20 @@ struct TriggerRequest {
     exe_id: Option<Uuid>,
 }
 
+#[derive(Deserialize)]
+struct TriggerParams {
+    // wait for the start task to be prepared before responding, reporting a worker that can't start as a 400
+    wait: Option<bool>,
+}
+
 #[derive(Serialize, Deserialize, Debug)]
 #[serde(rename_all = "camelCase")]
 pub struct ResumeWorker {
     pub token: String,
     pub custom_output: Option<Value>,
+    // react ID of a task to re-run the worker from instead of continuing after the suspended task. Can't be combined
+    // with `custom_output`, the suspended task is re-run rather than completed
+    pub resume_from: Option<String>,
 }
 
 #[derive(Serialize, Deserialize, Debug)]
@@ -90,6 +118,53 @@ async fn ws_index(
     resp
 }
 
+// Server-Sent Events alternative to the websocket endpoint for clients that can't use websockets.
+// Subscribes to the same live update server, so buffered logs are replayed the same way. The subscription is made
+// before responding so a client over the per-execution limit is told so rather than getting an empty stream
+#[get("/api/executions/{execution_id}/logs/stream")]
+async fn stream_logs(
+    id: Path<Uuid>,
+    _auth: Authenticated,
+    srv_data: Data<WebServerData>,
+) -> Result<HttpResponse, ApiError> {
+    let (sender, receiver) = tokio::sync::mpsc::unbounded_channel::<WorkerLog>();
+    // logs replayed on subscribing wait in the actor's mailbox until it's started
+    let ctx = actix::Context::<SseActor>::new();
+    let subscribed = srv_data
+        .ws_server
+        .send(Subscribe {
+            exe_id: id.into_inner(),
+            client: ctx.address().recipient(),
+        })
+        .await;
+    let session_id = match subscribed {
+        Ok(SubscribeResult::Subscribed(session_id)) => session_id,
+        Ok(SubscribeResult::LimitReached) => {
+            return Err(ApiError::TooManyRequests(String::from(
+                "Execution already has the maximum number of subscribers",
+            )))
+        }
+        Err(err) => return Err(ApiError::Unavailable(format!("Live updates unavailable: {}", err))),
+    };
+    ctx.run(SseActor {
+        id: session_id,
+        srv_addr: srv_data.ws_server.clone(),
+        sender,
+    });
+
+    let stream = futures::stream::unfold(receiver, |mut receiver| async move {
+        receiver
+            .recv()
+            .await
+            .map(|log| (Ok::<_, std::convert::Infallible>(sse_event(&log)), receiver))
+    });
+
+    Ok(HttpResponse::Ok()
+        .content_type("text/event-stream")
+        .insert_header(("Cache-Control", "no-cache"))
+        .streaming(stream))
+}
+
 #[derive(Debug, Serialize, Deserialize)]
 struct Claims {
     id: String,
@@ -98,144 +173,477 @@ struct Claims {
 }
 
 #[post("/api/resume")]
-async fn resume(resume_req: Json<ResumeWorker>, srv_data: Data<WebServerData>) -> impl Responder {
+async fn resume(resume_req: Json<ResumeWorker>, srv_data: Data<WebServerData>) -> Result<HttpResponse, ApiError> {
     let ws_addr = srv_data.ws_server.clone();
     // this is a temporary solution to validate the design, secret should not be hardcoded
     let decode_key = DecodingKey::from_secret("wow much secret".as_ref());
     let validation = Validation::default();
-    let token = decode::<Claims>(&resume_req.token, &decode_key, &validation).unwrap();
-    dbg!(&token);
-    let client = reqwest::Client::new();
+    let token = decode::<Claims>(&resume_req.token, &decode_key, &validation)?;
+    // the claims carry the user's bearer token, so only the run ID is logged
+    debug!(run_id = %token.claims.id, "worker token decoded");
+    let run_id = claim_run(&token.claims).await?;
+    // a run that couldn't be resumed is released so the token can be used again, unless it isn't waiting anyway
+    let (suspended_worker_inv, pending_output) = match prepare_resume(&resume_req, &token.claims).await {
+        Ok(prepared) => prepared,
+        Err(err) => {
+            if !matches!(err, ApiError::Conflict(_)) {
+                xpertly_worker::runs::release(run_id).await;
+            }
+            return Err(err);
+        }
+    };
+    // the run restores its secrets as it's resumed, failing if they can't be, see `xpertly_worker::secrets`
+    debug!("resuming worker");
+
+    // the run carries on on the worker runtime, it's left running once we've responded
+    xpertly_worker::spawn_resume(suspended_worker_inv, pending_output, Some(ws_addr.recipient()));
+
+    Ok(HttpResponse::Ok().json(json!({"message": "successfully resumed worker"})))
+}
+
+// claims the run a wait token is for, so a duplicate callback (to this or another instance) is rejected while the
+// run is going and after it's finished. See `xpertly_worker::runs`
+async fn claim_run(claims: &Claims) -> Result<Uuid, ApiError> {
+    let run_id = Uuid::parse_str(&claims.id)
+        .map_err(|_| ApiError::BadRequest(String::from("Token contains an invalid run ID")))?;
+    match xpertly_worker::runs::claim(run_id).await {
+        Ok(true) => Ok(run_id),
+        Ok(false) => Err(ApiError::Conflict(String::from("worker has already been resumed or cancelled"))),
+        Err(err) => Err(ApiError::Internal(format!("Failed to claim run: {}", err))),
+    }
+}
+
+// fetches the suspended invocation a resume request is for, along with the output to resume it with
+async fn prepare_resume(
+    resume_req: &ResumeWorker,
+    claims: &Claims,
+) -> Result<(xpertly_worker::WorkerInvocation, Value), ApiError> {
+    // re-running from an earlier task doesn't complete the suspended one, so it doesn't need an output
+    let pending_output = match (&resume_req.custom_output, &resume_req.resume_from) {
+        (Some(_), Some(_)) => {
+            return Err(ApiError::BadRequest(String::from(
+                "customOutput can't be given with resumeFrom, the suspended task is re-run rather than completed",
+            )))
+        }
+        (Some(custom_output), None) => custom_output.clone(),
+        (None, Some(_)) => json!({}),
+        (None, None) => return Err(ApiError::BadRequest(String::from("customOutput is required"))),
+    };
+
+    let auth = HeaderValue::from_str(claims.auth.as_str())
+        .map_err(|_| ApiError::BadRequest(String::from("Token contains an invalid authorization value")))?;
+    let client = xpertly_worker::client::build_client();
     let suspended_worker: serde_json::Value = client
         .get(format!(
             "https://api.dev.xpertly.io/v1/client/get_handler_payload/{}",
-            token.claims.id
+            claims.id
         ))
-        .header(
-            HeaderName::from_str("Authorization").unwrap(),
-            HeaderValue::from_str(token.claims.auth.as_str()).unwrap(),
-        )
+        .header(HeaderName::from_str("Authorization").unwrap(), auth)
         .send()
-        .await
-        .unwrap()
+        .await?
+        .error_for_status()?
         .json()
-        .await
-        .unwrap();
-
-    dbg!(&suspended_worker);
-
-    let suspended_worker_inv =
-        xpertly_worker::WorkerInvocation::from_suspended(suspended_worker).unwrap();
-    dbg!("Resuming Worker");
-
-    // spawn a thread to complete worker execution and return from this endpoint immediately,
-    // leaving the worker execution going in a detached thread.
-    
-    suspended_worker_inv.resume(
-            &resume_req.custom_output.as_ref().unwrap(),
-            Some(ws_addr.recipient()),
-        )
-        .await;
-
-    HttpResponse::Ok().json(json!({"message": "successfully resumed worker"}))
+        .await?;
+
+    debug!(?suspended_worker, "fetched suspended worker");
+
+    let mut suspended_worker_inv = xpertly_worker::WorkerInvocation::from_suspended(suspended_worker)
+        .map_err(|err| ApiError::Internal(format!("Invalid suspended worker: {}", err)))?;
+    // e.g. a duplicate callback for a run that was resumed and has since finished
+    let state = suspended_worker_inv.state();
+    if state != xpertly_worker::InvocationState::Waiting {
+        return Err(ApiError::Conflict(format!("Worker is {}, only waiting workers can be resumed", state)));
+    }
+    if let Some(resume_from) = &resume_req.resume_from {
+        suspended_worker_inv
+            .set_resume_from(resume_from)
+            .map_err(|err| ApiError::BadRequest(err.to_string()))?;
+    }
+    suspended_worker_inv
+        .validate_custom_output(&pending_output)
+        .map_err(|err| ApiError::BadRequest(err.to_string()))?;
+    Ok((suspended_worker_inv, pending_output))
 }
 
 #[post("/api/cancel")]
-async fn cancel(cancel_req: Json<CancelWorker>, srv_data: Data<WebServerData>) -> impl Responder {
+async fn cancel(cancel_req: Json<CancelWorker>, srv_data: Data<WebServerData>) -> Result<HttpResponse, ApiError> {
     let ws_addr = srv_data.ws_server.clone();
     // this is a temporary solution to validate the design, secret should not be hardcoded
     let decode_key = DecodingKey::from_secret("wow much secret".as_ref());
     let validation = Validation::default();
-    let token = decode::<Claims>(&cancel_req.token, &decode_key, &validation).unwrap();
-    dbg!(&token);
-    let client = reqwest::Client::new();
-    let suspended_worker: serde_json::Value = client
+    let token = decode::<Claims>(&cancel_req.token, &decode_key, &validation)?;
+    // the claims carry the user's bearer token, so only the run ID is logged
+    debug!(run_id = %token.claims.id, "worker token decoded");
+    let run_id = claim_run(&token.claims).await?;
+    let suspended_worker_inv = match prepare_cancel(&token.claims).await {
+        Ok(invocation) => invocation,
+        Err(err) => {
+            if !matches!(err, ApiError::Conflict(_)) {
+                xpertly_worker::runs::release(run_id).await;
+            }
+            return Err(err);
+        }
+    };
+    debug!("cancelling worker");
+
+    // the finalizer runs on the worker runtime, it's left running once we've responded
+    let message = cancel_req.message.clone().unwrap_or(Value::Null);
+    xpertly_worker::spawn_cancel(suspended_worker_inv, message, Some(ws_addr.recipient()));
+
+    Ok(HttpResponse::Ok().json(json!({"message": "successfully cancelled worker"})))
+}
+
+// fetches the suspended invocation a cancel request is for
+async fn prepare_cancel(claims: &Claims) -> Result<xpertly_worker::WorkerInvocation, ApiError> {
+    let auth = HeaderValue::from_str(claims.auth.as_str())
+        .map_err(|_| ApiError::BadRequest(String::from("Token contains an invalid authorization value")))?;
+    let suspended_worker: serde_json::Value = xpertly_worker::client::build_client()
         .get(format!(
             "https://api.dev.xpertly.io/v1/client/get_handler_payload/{}",
-            token.claims.id
+            claims.id
         ))
-        .header(
-            HeaderName::from_str("Authorization").unwrap(),
-            HeaderValue::from_str(token.claims.auth.as_str()).unwrap(),
-        )
+        .header(HeaderName::from_str("Authorization").unwrap(), auth)
         .send()
-        .await
-        .unwrap()
+        .await?
+        .error_for_status()?
         .json()
-        .await
-        .unwrap();
-
-    dbg!(&suspended_worker);
+        .await?;
+
+    let suspended_worker_inv = xpertly_worker::WorkerInvocation::from_suspended(suspended_worker)
+        .map_err(|err| ApiError::Internal(format!("Invalid suspended worker: {}", err)))?;
+    let state = suspended_worker_inv.state();
+    if state != xpertly_worker::InvocationState::Waiting {
+        return Err(ApiError::Conflict(format!("Worker is {}, only waiting workers can be cancelled", state)));
+    }
+    Ok(suspended_worker_inv)
+}
 
-    let suspended_worker_inv =
-        xpertly_worker::WorkerInvocation::from_suspended(suspended_worker).unwrap();
-    dbg!("Cancelling Worker");
+// outputs accumulated by a suspended worker run, so progress can be shown while it waits
+#[get("/api/runs/{run_id}/outputs")]
+async fn get_run_outputs(run_id: Path<Uuid>, auth: Authenticated) -> Result<HttpResponse, ApiError> {
+    let run_id = run_id.into_inner();
+    let token = HeaderValue::from_str(&auth.token)
+        .map_err(|_| ApiError::BadRequest(String::from("Token contains an invalid authorization value")))?;
+    let client = xpertly_worker::client::build_client();
+    let suspended_worker: Value = client
+        .get(format!(
+            "https://api.dev.xpertly.io/v1/client/get_handler_payload/{}",
+            run_id
+        ))
+        .header(HeaderName::from_str("Authorization").unwrap(), token)
+        .send()
+        .await?
+        .error_for_status()?
+        .json()
+        .await?;
 
-    // spawn a thread to complete worker execution and return from this endpoint immediately,
-    // leaving the worker execution going in a detached thread.
-    suspended_worker_inv
-        .cancel(
-            &cancel_req.message.as_ref().unwrap(),
-            Some(ws_addr.recipient()),
-        ).await;
+    let mut outputs = match suspended_worker.get("outputs") {
+        Some(outputs) => serde_json::from_value::<HashMap<String, Value>>(outputs.clone())?,
+        None => return Err(ApiError::NotFound(String::from("No suspended worker found for run"))),
+    };
+    // large outputs are only referenced in the payload
+    if let Err(err) = xpertly_worker::resolve_offloaded_outputs(run_id, &mut outputs).await {
+        return Err(match suspended_worker["state"].as_str() {
+            Some("complete") | Some("failed") => {
+                ApiError::NotFound(String::from("The run has finished, its offloaded outputs have been deleted"))
+            }
+            _ => ApiError::Internal(format!("Failed to fetch offloaded outputs: {}", err)),
+        });
+    }
+    Ok(HttpResponse::Ok().json(outputs))
+}
+// status of an execution started or resumed by this process. Finished executions are only kept for a while, see
+// `xpertly_worker::executions`. Only users of the execution's tenant can see it
+#[get("/api/executions/{execution_id}")]
+async fn get_execution(execution_id: Path<Uuid>, auth: Authenticated) -> Result<HttpResponse, ApiError> {
+    let execution_id = execution_id.into_inner();
+    match xpertly_worker::executions::lookup(execution_id) {
+        Lookup::Found(status) => {
+            fetch_user(status.tenant_id, &auth).await?;
+            Ok(HttpResponse::Ok().json(status))
+        }
+        Lookup::Expired => Err(ApiError::NotFound(format!(
+            "Execution {} expired, finished executions are kept for {} seconds",
+            execution_id,
+            xpertly_worker::executions::retention().num_seconds()
+        ))),
+        Lookup::Unknown => Err(ApiError::NotFound(String::from("Execution not found"))),
+    }
+}
 
-    HttpResponse::Ok().json(json!({"message": "successfully cancelled worker"}))
+// a recorded execution's logs, oldest first and paginated with `offset` and `limit`. Unlike the live streams this
+// also covers runs that have finished
+#[get("/api/executions/{execution_id}/history")]
+async fn get_execution_history(
+    execution_id: Path<Uuid>,
+    params: Query<HistoryParams>,
+    auth: Authenticated,
+    ws_srv: Data<WebServerData>,
+) -> Result<HttpResponse, ApiError> {
+    let execution_id = execution_id.into_inner();
+    let db = ws_srv.db.as_ref().ok_or(ApiError::NoDatabase)?;
+    // the record says which tenant's index the logs were written to
+    let record = db
+        .filter_item::<ExecutionRecord>(Some(doc! {"SK": ExecutionRecord::sort_key(&execution_id)}))
+        .await?
+        .ok_or_else(|| ApiError::NotFound(String::from("Execution not found")))?;
+    // only users of the execution's tenant can read its logs
+    fetch_user(record.worker.tenant_id, &auth).await?;
+    let history = history::fetch_history(&record.tenant_id, execution_id, &params).await?;
+    Ok(HttpResponse::Ok().json(history))
 }
+
 #[post("/api/tenants/{tenant_id}/workers/{worker_id}/trigger")]
 async fn trigger(
     req: HttpRequest,
     ids: Path<(Uuid, Uuid)>,
+    params: Query<TriggerParams>,
     trigger: Json<TriggerRequest>,
     auth: Authenticated,
     ws_srv: Data<WebServerData>,
-) -> impl Responder {
-    let ws_addr = ws_srv.ws_server.clone();
-    let worker = xpertly_worker::Worker::from_config(&trigger.worker).unwrap();
-
+) -> Result<HttpResponse, ApiError> {
+    let (tenant_id, worker_id) = ids.into_inner();
+    let trigger = trigger.into_inner();
     // TODO: don't take exe id from client
     let exe_id = trigger.exe_id.unwrap_or(Uuid::new_v4());
-    let client = reqwest::Client::new();
+    start_execution(
+        &trigger.worker,
+        trigger.tags,
+        exe_id,
+        tenant_id,
+        params.wait.unwrap_or(false),
+        auth,
+        &ws_srv,
+        None,
+    )
+    .await
+}
+
+#[derive(Deserialize)]
+struct RunRequest {
+    tags: Vec<String>,
+}
+
+// runs a worker stored with `create_worker`, accepting the same `wait` parameter as `trigger`
+#[post("/api/tenants/{tenant_id}/workers/{worker_id}/run")]
+async fn run_worker(
+    ids: Path<(Uuid, Uuid)>,
+    params: Query<TriggerParams>,
+    run: Json<RunRequest>,
+    auth: Authenticated,
+    ws_srv: Data<WebServerData>,
+) -> Result<HttpResponse, ApiError> {
     let (tenant_id, worker_id) = ids.into_inner();
-    let user_response = client
+    let db = ws_srv.db.as_ref().ok_or(ApiError::NoDatabase)?;
+    let definition = db
+        .filter_item::<WorkerDefinition>(Some(worker_filter(&tenant_id, &worker_id)))
+        .await?
+        .ok_or_else(|| ApiError::NotFound(String::from("Worker not found")))?;
+    start_execution(
+        &definition.config,
+        run.into_inner().tags,
+        Uuid::new_v4(),
+        tenant_id,
+        params.wait.unwrap_or(false),
+        auth,
+        &ws_srv,
+        None,
+    )
+    .await
+}
+
+#[derive(Deserialize, Default)]
+struct RetryRequest {
+    // tags to run the retry with, the original execution's tags are used if omitted
+    tags: Option<Vec<String>>,
+}
+
+// starts a fresh run of a recorded execution's worker, accepting the same `wait` parameter as `trigger`
+#[post("/api/executions/{execution_id}/retry")]
+async fn retry_execution(
+    execution_id: Path<Uuid>,
+    params: Query<TriggerParams>,
+    retry: Option<Json<RetryRequest>>,
+    auth: Authenticated,
+    ws_srv: Data<WebServerData>,
+) -> Result<HttpResponse, ApiError> {
+    let execution_id = execution_id.into_inner();
+    let db = ws_srv.db.as_ref().ok_or(ApiError::NoDatabase)?;
+    let record = db
+        .filter_item::<ExecutionRecord>(Some(doc! {"SK": ExecutionRecord::sort_key(&execution_id)}))
+        .await?
+        .ok_or_else(|| ApiError::NotFound(String::from("Execution not found")))?;
+    let tags = retry
+        .map(|retry| retry.into_inner())
+        .unwrap_or_default()
+        .tags
+        .unwrap_or(record.tags);
+    // the record's config has its secrets redacted
+    let mut worker = record.worker;
+    xpertly_worker::secrets::restore_config(execution_id, &mut worker)
+        .await
+        .map_err(|err| ApiError::Conflict(format!("Execution can't be retried: {}", err)))?;
+    start_execution(
+        &worker,
+        tags,
+        Uuid::new_v4(),
+        worker.tenant_id,
+        params.wait.unwrap_or(false),
+        auth,
+        &ws_srv,
+        Some(execution_id),
+    )
+    .await
+}
+
+// the authenticated user's Avicenna profile. Tokens close to expiring are often rejected by Avicenna even though they
+// got through our middleware, so a rejection is reported as a 401 rather than an upstream failure
+async fn fetch_user(tenant_id: Uuid, auth: &Authenticated) -> Result<AvicennaUser, ApiError> {
+    let user_id = &auth.claims.username;
+    let user_response = xpertly_worker::client::build_client()
         .get(format!(
             "https://api.dev.avicenna.io/v1/tenants/{tenant_id}/users/{user_id}",
             tenant_id = tenant_id,
-            user_id = auth.claims.username
+            user_id = user_id
         ))
         .header("Authorization", format!("Bearer {}", auth.token))
         .send()
         .await
-        .unwrap();
-
-    let resp_json = user_response.json::<Value>().await.unwrap();
-    let user = serde_json::from_value::<AvicennaUser>(resp_json).unwrap();
+        .map_err(|err| ApiError::BadGateway(format!("Failed to fetch user {} from Avicenna: {}", user_id, err)))?;
+
+    let status = user_response.status();
+    if status == reqwest::StatusCode::UNAUTHORIZED || status == reqwest::StatusCode::FORBIDDEN {
+        return Err(ApiError::Unauthorized(format!(
+            "Avicenna rejected the token when fetching user {} ({}), it may have expired",
+            user_id, status
+        )));
+    }
+    if !status.is_success() {
+        return Err(ApiError::BadGateway(format!(
+            "Failed to fetch user {} from Avicenna: {}",
+            user_id, status
+        )));
+    }
+
+    let resp_json = user_response
+        .json::<Value>()
+        .await
+        .map_err(|err| ApiError::BadGateway(format!("Avicenna returned an unreadable user {}: {}", user_id, err)))?;
+    serde_json::from_value::<AvicennaUser>(resp_json)
+        .map_err(|err| ApiError::BadGateway(format!("Avicenna returned an invalid user {}: {}", user_id, err)))
+}
 
-    // response
-    std::thread::spawn(move || {
-        xpertly_worker::execute(
-            &trigger.tags,
-            worker,
-            user,
+// starts executing a worker on behalf of the authenticated user and responds with the execution ID. With `wait`,
+// responds once the start task has been prepared, reporting a worker that can't start as a 400.
+// Executions are recorded when there's a database, so they can be retried
+async fn start_execution(
+    worker_config: &WorkerConfig,
+    tags: Vec<String>,
+    exe_id: Uuid,
+    tenant_id: Uuid,
+    wait: bool,
+    auth: Authenticated,
+    ws_srv: &WebServerData,
+    retry_of: Option<Uuid>,
+) -> Result<HttpResponse, ApiError> {
+    if let Some(vendor) = worker_config
+        .integration_vendors()
+        .into_iter()
+        .find(|vendor| !ENABLED_VENDORS.is_enabled(vendor))
+    {
+        return Err(ApiError::BadRequest(format!(
+            "Worker uses {} integrations, which are not enabled on this deployment",
+            vendor
+        )));
+    }
+    let worker = xpertly_worker::Worker::from_config(worker_config)
+        .map_err(|err| ApiError::BadRequest(err.to_string()))?;
+
+    let user = fetch_user(tenant_id, &auth).await?;
+
+    if wait {
+        if let Err(err) = xpertly_worker::check_start(
+            &worker,
+            tags.first().cloned(),
+            &user,
             &auth.token,
-            exe_id,
-            Some(ws_addr.recipient()),
-        );
-    });
+        )
+        .await
+        {
+            return Ok(HttpResponse::BadRequest().json(json!({
+                "executionId": exe_id,
+                "error": format!("Worker failed to start: {}", err),
+            })));
+        }
+    }
+
+    // a run that suspends can't be resumed without its secrets, so it isn't started if they can't be saved
+    xpertly_worker::secrets::save(exe_id, worker_config)
+        .await
+        .map_err(|err| ApiError::Internal(format!("Failed to save the worker's secrets: {}", err)))?;
+    if let Some(db) = &ws_srv.db {
+        let mut record = ExecutionRecord::new(exe_id, worker_config.clone(), tags.clone(), retry_of);
+        record.worker = xpertly_worker::secrets::redact_config(&record.worker);
+        // the run goes ahead regardless, it just can't be retried
+        if let Err(err) = db.insert_one(&record).await {
+            warn!(%exe_id, %err, "failed to record execution");
+        }
+    }
+
+    // response. The execution runs on the worker runtime, it's left running once we've responded
+    let ws_addr = ws_srv.ws_server.clone();
+    xpertly_worker::spawn_execute(
+        &tags,
+        worker,
+        user,
+        &auth.token,
+        exe_id,
+        Some(ws_addr.recipient()),
+    );
 
-    HttpResponse::Ok().json(json!({ "executionId": exe_id }))
+    if wait {
+        Ok(HttpResponse::Accepted().json(json!({ "executionId": exe_id })))
+    } else {
+        Ok(HttpResponse::Ok().json(json!({ "executionId": exe_id })))
+    }
+}
+
+#[get("/api/health")]
+async fn health() -> HttpResponse {
+    HttpResponse::Ok().json(json!({
+        "status": "ok",
+        "inFlightExecutions": xpertly_worker::in_flight_executions(),
+        "maxConcurrentExecutions": xpertly_worker::max_concurrent_executions(),
+        // runs suspended by this process and how many of those are past their callback deadline
+        "waitingWorkers": xpertly_worker::waiting::waiting_runs(),
+        "stuckWaitingWorkers": xpertly_worker::waiting::stuck_waiting_runs(),
+        // executions kept for status queries, running or recently finished
+        "trackedExecutions": xpertly_worker::executions::tracked_executions(),
+    }))
 }
 
 #[actix_web::main]
 async fn main() -> std::io::Result<()> {
+    // log level is configured with RUST_LOG, e.g. RUST_LOG=debug to include request/response payloads.
+    // tracing events are forwarded to env_logger through tracing's `log` feature
     env_logger::init_from_env(env_logger::Env::default().default_filter_or("info"));
     dotenv().ok();
 
-    let client = reqwest::Client::new();
-    let jwks: serde_json::Value = client.get("https://cognito-idp.ap-southeast-2.amazonaws.com/ap-southeast-2_rf7hpngbY/.well-known/jwks.json").send().await.unwrap().json().await.unwrap();
-    dbg!(&jwks);
-    let jwks = serde_json::from_value::<Vec<XpertlyJwk>>(jwks["keys"].clone()).unwrap();
+    let client = xpertly_worker::client::build_client();
+    let jwks = match fetch_jwks_with_retry(&client).await {
+        Ok(jwks) => jwks,
+        Err(err) => {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::Other,
+                format!("Failed to fetch Cognito JWKS, can't authenticate requests: {}", err),
+            ))
+        }
+    };
+    let key_store: KeyStore = std::sync::Arc::new(std::sync::RwLock::new(jwks));
+    refresh_periodically(client, key_store.clone());
 
     let ws_server = LiveUpdateServer::new().start();
+    xpertly_worker::waiting::sweep_periodically(Some(ws_server.clone().recipient()));
 
     let uri = match env::var("MONGOURI") {
         Ok(v) => Some(v.to_string()),
@@ -247,6 +655,15 @@ async fn main() -> std::io::Result<()> {
 
     let server_data = if let Some(uri) = &uri {
         let db = MongoDbClient::init(&uri, "rustDB").await;
+        if let Err(e) = db.ensure_indexes().await {
+            warn!(error = %e, "failed to create database indexes");
+        }
+        // resume and cancel claims are shared with the other instances and kept across restarts
+        xpertly_worker::runs::set_store(std::sync::Arc::new(xpertly_worker::runs::MongoRunStore::new(db.clone())));
+        // and so are the secrets suspended runs are resumed with
+        xpertly_worker::secrets::set_store(std::sync::Arc::new(xpertly_worker::secrets::MongoSecretStore::new(
+            db.clone(),
+        )));
         WebServerData {
             ws_server,
             db: Some(db),
@@ -262,16 +679,22 @@ async fn main() -> std::io::Result<()> {
         App::new()
             .app_data(Data::new(server_data.clone()))
             .wrap(Logger::default())
-            .wrap(AuthenticateMiddlewareFactory::new(jwks.clone()))
+            .wrap(AuthenticateMiddlewareFactory::new(key_store.clone()))
+            .service(health)
             .service(trigger)
             .service(ws_index)
+            .service(stream_logs)
             .service(resume)
             .service(cancel)
+            .service(get_run_outputs)
+            .service(get_execution)
+            .service(get_execution_history)
             // .service(test)
             // .service(get_user)
             // .service(update_user)
             // .service(create_user)
             .service(create_asset)
+            .service(update_asset)
             .service(create_device)
             .service(get_assets)
             .service(create_asset_tag)
@@ -281,6 +704,14 @@ async fn main() -> std::io::Result<()> {
             .service(create_integration)
             .service(get_integrations)
             .service(get_integration)
+            .service(update_integration)
+            .service(create_worker)
+            .service(get_worker)
+            .service(get_capabilities)
+            .service(preview_task)
+            .service(run_task)
+            .service(run_worker)
+            .service(retry_execution)
     })
     .bind(("0.0.0.0", 8000))?
     .run()
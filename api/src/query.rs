@@ -0,0 +1,89 @@
+use std::future::{ready, Ready};
+
+use actix_web::{FromRequest, HttpRequest};
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Deserializer};
+use serde_json::{Map, Value};
+
+use crate::error::ApiError;
+
+/// Query string extractor that, unlike `web::Query`, accepts repeated keys (`?tags=a&tags=b`). Keys given once are
+/// deserialized as strings and repeated keys as lists, so list fields should use `one_or_many` to also accept a key
+/// given once
+#[derive(Debug)]
+pub struct QueryString<T>(pub T);
+
+impl<T: DeserializeOwned> FromRequest for QueryString<T> {
+    type Error = ApiError;
+    type Future = Ready<Result<Self, Self::Error>>;
+
+    fn from_request(req: &HttpRequest, _: &mut actix_web::dev::Payload) -> Self::Future {
+        ready(with_query_string(req.query_string()).map(QueryString))
+    }
+}
+
+impl<T> std::ops::Deref for QueryString<T> {
+    type Target = T;
+
+    fn deref(&self) -> &Self::Target {
+        &self.0
+    }
+}
+
+// decodes a query string, percent encoded characters and `+` included, into `T`
+pub fn with_query_string<T: DeserializeOwned>(query: &str) -> Result<T, ApiError> {
+    let mut params = Map::new();
+    for (key, value) in form_urlencoded::parse(query.as_bytes()) {
+        let value = Value::String(value.into_owned());
+        match params.get_mut(&*key) {
+            Some(Value::Array(values)) => values.push(value),
+            Some(existing) => *existing = Value::Array(vec![existing.take(), value]),
+            None => {
+                params.insert(key.into_owned(), value);
+            }
+        }
+    }
+    serde_json::from_value(Value::Object(params))
+        .map_err(|err| ApiError::BadRequest(format!("Invalid query string: {}", err)))
+}
+
+// deserializes a list from a query key given either once or repeatedly
+pub fn one_or_many<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Vec<String>, D::Error> {
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum OneOrMany {
+        One(String),
+        Many(Vec<String>),
+    }
+
+    Ok(match OneOrMany::deserialize(deserializer)? {
+        OneOrMany::One(value) => vec![value],
+        OneOrMany::Many(values) => values,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[derive(Deserialize)]
+    struct TagQuery {
+        #[serde(deserialize_with = "one_or_many")]
+        tags: Vec<String>,
+        #[serde(rename = "match")]
+        match_mode: Option<String>,
+    }
+
+    #[test]
+    fn test_repeated_tags() {
+        let query: TagQuery = with_query_string("tags=office&tags=branch%20%26%20depot&tags=a%3Db&match=all").unwrap();
+        assert_eq!(query.tags, vec!["office", "branch & depot", "a=b"]);
+        assert_eq!(query.match_mode.as_deref(), Some("all"));
+
+        let query: TagQuery = with_query_string("tags=office").unwrap();
+        assert_eq!(query.tags, vec!["office"]);
+        assert!(query.match_mode.is_none());
+
+        assert!(with_query_string::<TagQuery>("match=all").is_err());
+    }
+}
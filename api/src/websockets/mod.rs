@@ -1,2 +1,3 @@
 pub mod server;
-pub mod session;
\ No newline at end of file
+pub mod session;
+pub mod sse;
\ No newline at end of file
@@ -1,12 +1,16 @@
-use std::collections::{HashMap, HashSet};
+use std::collections::{HashMap, HashSet, VecDeque};
 
 use actix::prelude::*;
 use actix_web_actors::ws;
 use uuid::Uuid;
-use xpertly_worker::{ WorkerLog, Publish };
+use xpertly_worker::{ WorkerLog, Publish, Event, InvocationState };
 
 type ClientSocket = Recipient<WorkerLog>;
 
+const DEFAULT_MAX_SUBSCRIBERS_PER_EXECUTION: usize = 50;
+const DEFAULT_MAX_BUFFERED_MESSAGES: usize = 10_000;
+const DEFAULT_MAX_REPLAYED_MESSAGES: usize = 100;
+
 #[derive(Message)]
 #[rtype(result = "SubscribeResult")]
 pub struct Subscribe {
@@ -14,7 +18,11 @@ pub struct Subscribe {
     pub client: ClientSocket,
 }
 
-pub struct SubscribeResult(pub Uuid);
+pub enum SubscribeResult {
+    Subscribed(Uuid),
+    // the execution already has the maximum number of subscribers
+    LimitReached,
+}
 
 #[derive(Message)]
 #[rtype(result = "()")]
@@ -22,22 +30,147 @@ pub struct Unsubscribe {
     pub id: Uuid,
 }
 
+/// Limits on the state held by the `LiveUpdateServer`, read from the `MAX_SUBSCRIBERS_PER_EXECUTION`,
+/// `MAX_BUFFERED_MESSAGES` and `MAX_REPLAYED_MESSAGES` env vars.
+///
+/// The last `max_replayed_messages` messages published for each execution are buffered and replayed to every
+/// client that subscribes to it, whether or not anyone was subscribed when they were published. Once
+/// `max_buffered_messages` are buffered across all executions, the oldest buffered message is dropped to make room
+/// for each new one, so a subscriber arriving late may miss the start of a long running execution.
+/// An execution's state is dropped once the runs of all its tags have finished and nobody is subscribed to it,
+/// including anything buffered for it.
+#[derive(Debug, Clone)]
+pub struct LiveUpdateLimits {
+    pub max_subscribers_per_execution: usize,
+    pub max_buffered_messages: usize,
+    pub max_replayed_messages: usize,
+}
+
+impl LiveUpdateLimits {
+    pub fn from_env() -> Self {
+        let limit = |var: &str, default: usize| {
+            std::env::var(var)
+                .ok()
+                .and_then(|limit| limit.parse::<usize>().ok())
+                .filter(|limit| *limit > 0)
+                .unwrap_or(default)
+        };
+        LiveUpdateLimits {
+            max_subscribers_per_execution: limit("MAX_SUBSCRIBERS_PER_EXECUTION", DEFAULT_MAX_SUBSCRIBERS_PER_EXECUTION),
+            max_buffered_messages: limit("MAX_BUFFERED_MESSAGES", DEFAULT_MAX_BUFFERED_MESSAGES),
+            max_replayed_messages: limit("MAX_REPLAYED_MESSAGES", DEFAULT_MAX_REPLAYED_MESSAGES),
+        }
+    }
+}
+
 pub struct LiveUpdateServer {
+    limits: LiveUpdateLimits,
     // collection of connected clients
     sessions: HashMap<Uuid, ClientSocket>,
     // mapping of execution ID to clients subscribed to that execution
     subscriptions: HashMap<Uuid, HashSet<Uuid>>,
-    // buffered message of subscribers for execution ID
-    buffered_message: HashMap<Uuid, Vec<WorkerLog>>,
+    // the last messages published for each execution ID, replayed to each new subscriber, tagged with the order they
+    // were published in
+    buffered_message: HashMap<Uuid, VecDeque<(u64, WorkerLog)>>,
+    // number of messages in `buffered_message` across all executions
+    buffered_count: usize,
+    next_sequence: u64,
+    // runs of each execution that have published something but haven't finished, see `track_run`
+    running: HashMap<Uuid, HashSet<Uuid>>,
+    // subscribed executions whose runs have all finished, evicted once their last subscriber leaves
+    finished: HashSet<Uuid>,
 }
 
 impl LiveUpdateServer {
     pub fn new() -> Self {
+        Self::with_limits(LiveUpdateLimits::from_env())
+    }
+
+    pub fn with_limits(limits: LiveUpdateLimits) -> Self {
         Self {
+            limits,
             sessions: HashMap::new(),
             subscriptions: HashMap::new(),
             buffered_message: HashMap::new(),
+            buffered_count: 0,
+            next_sequence: 0,
+            running: HashMap::new(),
+            finished: HashSet::new(),
+        }
+    }
+
+    fn buffer(&mut self, exe_id: Uuid, msg: WorkerLog) {
+        let replayed = self.buffered_message.get(&exe_id).map_or(0, |messages| messages.len());
+        if replayed >= self.limits.max_replayed_messages {
+            self.remove_oldest_buffered(&exe_id);
+        } else if self.buffered_count >= self.limits.max_buffered_messages {
+            self.drop_oldest();
+        }
+        self.buffered_message
+            .entry(exe_id)
+            .or_insert_with(VecDeque::new)
+            .push_back((self.next_sequence, msg));
+        self.next_sequence += 1;
+        self.buffered_count += 1;
+    }
+
+    // drops the oldest buffered message across all executions. Each execution's buffer is in publish order,
+    // so the oldest message is at the front of one of them
+    fn drop_oldest(&mut self) {
+        let oldest = self
+            .buffered_message
+            .iter()
+            .filter_map(|(exe_id, messages)| messages.front().map(|(sequence, _)| (*sequence, *exe_id)))
+            .min();
+        if let Some((_, exe_id)) = oldest {
+            self.remove_oldest_buffered(&exe_id);
+        }
+    }
+
+    // removes an execution's oldest buffered message, and its buffer once that's empty
+    fn remove_oldest_buffered(&mut self, exe_id: &Uuid) {
+        if let Some(messages) = self.buffered_message.get_mut(exe_id) {
+            if messages.pop_front().is_some() {
+                self.buffered_count -= 1;
+            }
+            if messages.is_empty() {
+                self.buffered_message.remove(exe_id);
+            }
+        }
+    }
+
+    // an execution runs once per tag, each run publishing under the execution's ID, so the execution has only finished
+    // once every run it's published for has. Returns whether that's the case after `msg`. Runs are only known from
+    // their first message, so a run still waiting to start when the others finish isn't waited for
+    fn track_run(&mut self, exe_id: Uuid, msg: &WorkerLog) -> bool {
+        let runs = self.running.entry(exe_id).or_insert_with(HashSet::new);
+        if !is_terminal(msg) {
+            runs.insert(msg.worker_run_id);
+            return false;
+        }
+        runs.remove(&msg.worker_run_id);
+        if !runs.is_empty() {
+            return false;
         }
+        self.running.remove(&exe_id);
+        true
+    }
+
+    // forgets everything held for an execution nothing more will be published for
+    fn evict(&mut self, exe_id: &Uuid) {
+        self.finished.remove(exe_id);
+        self.running.remove(exe_id);
+        if let Some(messages) = self.buffered_message.remove(exe_id) {
+            self.buffered_count -= messages.len();
+        }
+    }
+}
+
+// the result of a run that has finished rather than being suspended, the last message published for that run
+fn is_terminal(msg: &WorkerLog) -> bool {
+    match (&msg.event, &msg.result) {
+        (Event::WorkerResult, Some(result)) => result.state != InvocationState::Waiting,
+        _ => false,
     }
 }
 
@@ -49,6 +182,11 @@ impl Handler<Subscribe> for LiveUpdateServer {
     type Result = MessageResult<Subscribe>;
 
     fn handle(&mut self, msg: Subscribe, _ctx: &mut Self::Context) -> Self::Result {
+        let subscribers = self.subscriptions.get(&msg.exe_id).map_or(0, |clients| clients.len());
+        if subscribers >= self.limits.max_subscribers_per_execution {
+            return MessageResult(SubscribeResult::LimitReached);
+        }
+
         let session_id = Uuid::new_v4();
         self.sessions.insert(session_id, msg.client.clone());
         self.subscriptions
@@ -56,14 +194,13 @@ impl Handler<Subscribe> for LiveUpdateServer {
             .or_insert_with(HashSet::new)
             .insert(session_id);
 
-        //send buffered message to subscriber
+        // replay the last messages to the subscriber, they're kept for whoever subscribes next
         if let Some(buffered_msg) = self.buffered_message.get(&msg.exe_id) {
-            for buf_msg in buffered_msg {
+            for (_, buf_msg) in buffered_msg {
                 msg.client.do_send(buf_msg.clone());
             }
-            self.buffered_message.remove(&msg.exe_id);
         }
-        MessageResult(SubscribeResult(session_id))
+        MessageResult(SubscribeResult::Subscribed(session_id))
     }
 }
 
@@ -73,24 +210,31 @@ impl Handler<Unsubscribe> for LiveUpdateServer {
     fn handle(&mut self, msg: Unsubscribe, _ctx: &mut Self::Context) {
         self.sessions.remove(&msg.id);
 
-        // remove client from any subscription it's a part of, 
+        // remove client from any subscription it's a part of,
         // also removing that subscription entirely if it's empty
-        self.subscriptions.retain(|subscrib_uuid, clients| {
+        let mut unsubscribed = Vec::new();
+        self.subscriptions.retain(|exe_id, clients| {
             clients.remove(&msg.id);
-            //remove cbuffered message session when there is no clients subscribed to
-            if clients.is_empty() == true {
-                self.buffered_message.remove(&subscrib_uuid);
+            if clients.is_empty() {
+                unsubscribed.push(*exe_id);
             }
             !clients.is_empty()
         });
+
+        // nothing more will be published for a finished execution, so it's forgotten once nobody is watching
+        for exe_id in unsubscribed {
+            if self.finished.contains(&exe_id) {
+                self.evict(&exe_id);
+            }
+        }
     }
 }
 
 impl Handler<Publish> for LiveUpdateServer {
     type Result = ();
 
-    fn handle(&mut self, msg: Publish, ctx: &mut Self::Context) -> Self::Result {
-        println!("received publish message");
+    fn handle(&mut self, msg: Publish, _ctx: &mut Self::Context) -> Self::Result {
+        let finished = self.track_run(msg.id, &msg.msg);
         match self.subscriptions.get(&msg.id) {
             Some(clients) => {
                 for client in clients {
@@ -99,11 +243,211 @@ impl Handler<Publish> for LiveUpdateServer {
                     }
                 }
             }
-            _ => self
-                .buffered_message
-                .entry(msg.id.clone())
-                .or_insert_with(Vec::new)
-                .push(msg.msg.clone()),
+            // a finished execution nobody subscribed to won't be watched, so what was buffered for it is dropped
+            None if finished => return self.evict(&msg.id),
+            None => {}
+        }
+        if finished {
+            self.finished.insert(msg.id);
+        }
+        self.buffer(msg.id, msg.msg);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    // drops whatever it's sent
+    struct Client;
+
+    impl Actor for Client {
+        type Context = Context<Self>;
+    }
+
+    impl Handler<WorkerLog> for Client {
+        type Result = ();
+
+        fn handle(&mut self, _msg: WorkerLog, _ctx: &mut Self::Context) {}
+    }
+
+    // keeps the events it's sent, handing them back on `Received`
+    #[derive(Default)]
+    struct Recorder(Vec<String>);
+
+    impl Actor for Recorder {
+        type Context = Context<Self>;
+    }
+
+    impl Handler<WorkerLog> for Recorder {
+        type Result = ();
+
+        fn handle(&mut self, msg: WorkerLog, _ctx: &mut Self::Context) {
+            self.0.push(serde_json::to_value(msg.event).unwrap().as_str().unwrap().to_string());
+        }
+    }
+
+    #[derive(Message)]
+    #[rtype(result = "Vec<String>")]
+    struct Received;
+
+    impl Handler<Received> for Recorder {
+        type Result = MessageResult<Received>;
+
+        fn handle(&mut self, _msg: Received, _ctx: &mut Self::Context) -> Self::Result {
+            MessageResult(self.0.clone())
+        }
+    }
+
+    fn log(exe_id: Uuid, run_id: Uuid, event: &str, state: Option<&str>) -> WorkerLog {
+        let result = state.map(|state| {
+            json!({
+                "executionId": exe_id,
+                "runId": run_id,
+                "tag": null,
+                "state": state,
+                "completedTasks": [],
+                "failedTask": null,
+                "outputsSummary": {},
+                "durationMs": 0,
+            })
+        });
+        serde_json::from_value(json!({
+            "@timestamp": "2023-06-01T00:00:00Z",
+            "tenantId": Uuid::new_v4(),
+            "workerName": "worker",
+            "workerId": Uuid::new_v4(),
+            "executionId": exe_id,
+            "workerRunId": run_id,
+            "taskName": null,
+            "taskType": null,
+            "reactId": null,
+            "runBy": "user",
+            "runByUserId": Uuid::new_v4(),
+            "tag": "None",
+            "event": event,
+            "reason": null,
+            "outputs": null,
+            "result": result,
+        }))
+        .unwrap()
+    }
+
+    fn publish(exe_id: Uuid, run_id: Uuid, event: &str, state: Option<&str>) -> Publish {
+        Publish { id: exe_id, msg: log(exe_id, run_id, event, state) }
+    }
+
+    fn limits() -> LiveUpdateLimits {
+        LiveUpdateLimits {
+            max_subscribers_per_execution: 5,
+            max_buffered_messages: 100,
+            max_replayed_messages: 10,
         }
     }
-}
\ No newline at end of file
+
+    #[actix::test]
+    async fn test_replay_to_every_subscriber() {
+        let mut server = LiveUpdateServer::with_limits(LiveUpdateLimits { max_replayed_messages: 2, ..limits() });
+        let mut ctx = Context::new();
+        let exe_id = Uuid::new_v4();
+        let run_id = Uuid::new_v4();
+        let subscribe = |server: &mut LiveUpdateServer, ctx: &mut Context<LiveUpdateServer>| {
+            let recorder = Recorder::default().start();
+            server.handle(Subscribe { exe_id, client: recorder.clone().recipient() }, ctx);
+            recorder
+        };
+
+        server.handle(publish(exe_id, run_id, "worker_start", None), &mut ctx);
+        server.handle(publish(exe_id, run_id, "task_start", None), &mut ctx);
+        let first = subscribe(&mut server, &mut ctx);
+        server.handle(publish(exe_id, run_id, "task_success", None), &mut ctx);
+        // only the last two messages are replayed, to the second subscriber as much as the first
+        let second = subscribe(&mut server, &mut ctx);
+        assert_eq!(server.buffered_count, 2);
+
+        assert_eq!(first.send(Received).await.unwrap(), vec!["worker_start", "task_start", "task_success"]);
+        assert_eq!(second.send(Received).await.unwrap(), vec!["task_start", "task_success"]);
+    }
+
+    #[actix::test]
+    async fn test_finished_executions_evicted() {
+        let mut server = LiveUpdateServer::with_limits(limits());
+        let mut ctx = Context::new();
+        let run_id = Uuid::new_v4();
+
+        // an execution that finishes with nobody subscribed leaves nothing behind
+        let unwatched = Uuid::new_v4();
+        server.handle(publish(unwatched, run_id, "worker_start", None), &mut ctx);
+        server.handle(publish(unwatched, run_id, "worker_success", None), &mut ctx);
+        assert_eq!(server.buffered_count, 2);
+        server.handle(publish(unwatched, run_id, "worker_result", Some("complete")), &mut ctx);
+        assert!(server.buffered_message.is_empty());
+        assert_eq!(server.buffered_count, 0);
+        assert!(server.finished.is_empty());
+        assert!(server.running.is_empty());
+
+        // a suspended run carries on later, so what's buffered for it is kept
+        let suspended = Uuid::new_v4();
+        server.handle(publish(suspended, run_id, "worker_result", Some("waiting")), &mut ctx);
+        assert_eq!(server.buffered_count, 1);
+
+        // a watched execution is forgotten once its last subscriber leaves after it finishes
+        let watched = Uuid::new_v4();
+        let client = Client.start().recipient();
+        let subscribe = |server: &mut LiveUpdateServer, ctx: &mut Context<LiveUpdateServer>| {
+            match server.handle(Subscribe { exe_id: watched, client: client.clone() }, ctx).0 {
+                SubscribeResult::Subscribed(id) => id,
+                SubscribeResult::LimitReached => panic!("limit reached"),
+            }
+        };
+        let first = subscribe(&mut server, &mut ctx);
+        let second = subscribe(&mut server, &mut ctx);
+        server.handle(publish(watched, run_id, "worker_result", Some("failed")), &mut ctx);
+        assert!(server.finished.contains(&watched));
+        server.handle(Unsubscribe { id: first }, &mut ctx);
+        assert!(server.finished.contains(&watched));
+        server.handle(Unsubscribe { id: second }, &mut ctx);
+        assert!(server.finished.is_empty());
+        assert!(server.subscriptions.is_empty());
+        assert!(server.sessions.is_empty());
+        assert_eq!(server.buffered_count, 1);
+    }
+
+    #[actix::test]
+    async fn test_finished_once_every_tag_is() {
+        let mut server = LiveUpdateServer::with_limits(limits());
+        let mut ctx = Context::new();
+        let (office, warehouse) = (Uuid::new_v4(), Uuid::new_v4());
+
+        // both tags of an execution nobody is watching have started, only one has finished
+        let unwatched = Uuid::new_v4();
+        server.handle(publish(unwatched, office, "worker_start", None), &mut ctx);
+        server.handle(publish(unwatched, warehouse, "worker_start", None), &mut ctx);
+        server.handle(publish(unwatched, office, "worker_result", Some("complete")), &mut ctx);
+        assert_eq!(server.buffered_count, 3);
+        assert!(server.running[&unwatched].contains(&warehouse));
+        server.handle(publish(unwatched, warehouse, "worker_result", Some("failed")), &mut ctx);
+        assert_eq!(server.buffered_count, 0);
+        assert!(server.running.is_empty());
+
+        // a watched execution isn't finished while one of its tags is still running
+        let watched = Uuid::new_v4();
+        let client = Client.start().recipient();
+        let session = match server.handle(Subscribe { exe_id: watched, client }, &mut ctx).0 {
+            SubscribeResult::Subscribed(id) => id,
+            SubscribeResult::LimitReached => panic!("limit reached"),
+        };
+        server.handle(publish(watched, office, "worker_start", None), &mut ctx);
+        server.handle(publish(watched, warehouse, "worker_start", None), &mut ctx);
+        server.handle(publish(watched, office, "worker_result", Some("complete")), &mut ctx);
+        assert!(!server.finished.contains(&watched));
+        // nor is it while a tag is suspended
+        server.handle(publish(watched, warehouse, "worker_result", Some("waiting")), &mut ctx);
+        assert!(!server.finished.contains(&watched));
+        server.handle(publish(watched, warehouse, "worker_result", Some("complete")), &mut ctx);
+        assert!(server.finished.contains(&watched));
+        server.handle(Unsubscribe { id: session }, &mut ctx);
+        assert!(server.buffered_message.is_empty());
+    }
+}
@@ -22,9 +22,16 @@ impl Actor for WsActor {
             .into_actor(self)
             .then(|res, act, ctx| {
                 match res {
-                    Ok(SubscribeResult(session_id)) => {
+                    Ok(SubscribeResult::Subscribed(session_id)) => {
                         act.id = session_id;
                     }
+                    Ok(SubscribeResult::LimitReached) => {
+                        ctx.close(Some(ws::CloseReason {
+                            code: ws::CloseCode::Again,
+                            description: Some(String::from("too many subscribers for this execution")),
+                        }));
+                        ctx.stop();
+                    }
                     _ => ctx.stop(),
                 }
                 fut::ready(())
@@ -0,0 +1,54 @@
+use actix::prelude::*;
+use actix_web::web::Bytes;
+use std::time::Duration;
+use tokio::sync::mpsc::UnboundedSender;
+use uuid::Uuid;
+pub use xpertly_worker::WorkerLog;
+use super::server::*;
+
+// how often to check whether the client has gone away when no logs are being published
+const DISCONNECT_CHECK_INTERVAL: Duration = Duration::from_secs(15);
+
+/// Server-Sent Events counterpart to `WsActor`. Started once `stream_logs` has subscribed it to the
+/// `LiveUpdateServer`, and forwards logs to the response stream through `sender`.
+pub struct SseActor {
+    // the session the subscription was made under
+    pub id: Uuid,
+    pub srv_addr: Addr<LiveUpdateServer>,
+    pub sender: UnboundedSender<WorkerLog>,
+}
+
+impl Actor for SseActor {
+    type Context = Context<Self>;
+
+    fn started(&mut self, ctx: &mut Self::Context) {
+        // the receiving half is dropped when the client disconnects
+        ctx.run_interval(DISCONNECT_CHECK_INTERVAL, |act, ctx| {
+            if act.sender.is_closed() {
+                ctx.stop();
+            }
+        });
+    }
+
+    fn stopping(&mut self, ctx: &mut Self::Context) -> Running {
+        self.srv_addr.do_send(Unsubscribe {
+            id: self.id,
+        });
+        Running::Stop
+    }
+}
+
+impl Handler<WorkerLog> for SseActor {
+    type Result = ();
+
+    fn handle(&mut self, msg: WorkerLog, ctx: &mut Self::Context) {
+        if self.sender.send(msg).is_err() {
+            ctx.stop();
+        }
+    }
+}
+
+/// Formats a log as a single SSE `data` event
+pub fn sse_event(log: &WorkerLog) -> Bytes {
+    Bytes::from(format!("data: {}\n\n", serde_json::to_string(log).unwrap()))
+}
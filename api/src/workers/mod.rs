@@ -0,0 +1,146 @@
+use actix_web::web::Json;
+use actix_web::web::{Data, Path};
+use actix_web::{get, post, HttpResponse};
+use mongodb::bson::{doc, Document};
+use serde::Deserialize;
+use serde_json::{json, Value};
+use std::collections::HashMap;
+use uuid::Uuid;
+use xpertly_common::{Comparitor, TaskConfig, WorkerConfig, WorkerDefinition};
+use xpertly_worker::task::{Filter, Handler, Task};
+use xpertly_worker::secrets::redact_config;
+use xpertly_worker::{render_task, RenderContext};
+
+use crate::auth::extractor::Authenticated;
+use crate::error::ApiError;
+use crate::{fetch_user, WebServerData};
+
+pub fn worker_filter(tenant_id: &Uuid, worker_id: &Uuid) -> Document {
+    doc! {"PK": tenant_id.to_string(), "SK": WorkerDefinition::sort_key(worker_id)}
+}
+
+#[post("/api/tenants/{tenant_id}/workers/create")]
+pub async fn create_worker(
+    ws_data: Data<WebServerData>,
+    tenant_id: Path<Uuid>,
+    data: Json<WorkerConfig>,
+) -> Result<HttpResponse, ApiError> {
+    let tenant_id = tenant_id.into_inner();
+    let db = ws_data.db.as_ref().ok_or(ApiError::NoDatabase)?;
+    let config = data.into_inner();
+    if config.tenant_id != tenant_id {
+        return Err(ApiError::BadRequest(String::from("Worker belongs to a different tenant")));
+    }
+    // reject configs that couldn't be run later
+    xpertly_worker::Worker::from_config(&config).map_err(|err| ApiError::BadRequest(err.to_string()))?;
+
+    let existing = db
+        .filter_item::<WorkerDefinition>(Some(worker_filter(&tenant_id, &config.id)))
+        .await?;
+    if existing.is_some() {
+        return Err(ApiError::Conflict(format!("Worker {} already exists", config.id)));
+    }
+
+    let definition = WorkerDefinition::new(config);
+    db.insert_one(&definition).await?;
+    // stored with its secrets, as it's run from here, but they're never sent back
+    Ok(HttpResponse::Ok().json(redact_config(&definition.config)))
+}
+
+#[get("/api/tenants/{tenant_id}/workers/{worker_id}")]
+pub async fn get_worker(
+    ws_data: Data<WebServerData>,
+    path: Path<(Uuid, Uuid)>,
+) -> Result<HttpResponse, ApiError> {
+    let (tenant_id, worker_id) = path.into_inner();
+    let db = ws_data.db.as_ref().ok_or(ApiError::NoDatabase)?;
+    match db
+        .filter_item::<WorkerDefinition>(Some(worker_filter(&tenant_id, &worker_id)))
+        .await?
+    {
+        Some(definition) => Ok(HttpResponse::Ok().json(redact_config(&definition.config))),
+        None => Err(ApiError::NotFound(String::from("Worker not found"))),
+    }
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct PreviewTaskRequest {
+    task: TaskConfig,
+    // sample outputs keyed by task name, as referenced by {{OUTPUT:<task name>.<path>}}
+    #[serde(default)]
+    outputs: HashMap<String, Value>,
+    // keyed as in the worker's `global` and `custom`
+    global: Option<Value>,
+    custom: Option<Value>,
+    // keyed by vendor then asset type, as referenced by {{ASSET:<vendor>.<asset type>.<attribute>}}
+    asset: Option<HashMap<String, HashMap<String, Value>>>,
+    tag_name: Option<String>,
+}
+
+/// The task with its variables substituted from sample values, so the worker builder can show what it will run.
+/// Variables without a sample value render as `undefined`, as they would when running
+#[post("/api/workers/preview-task")]
+pub async fn preview_task(preview: Json<PreviewTaskRequest>) -> Result<HttpResponse, ApiError> {
+    let preview = preview.into_inner();
+    let mut task = Task::from_config(preview.task).map_err(|err| ApiError::BadRequest(format!("Invalid task: {}", err)))?;
+    task.asset_vars = preview.asset;
+    let context = RenderContext {
+        task_ids: preview.outputs.keys().map(|name| (name.clone(), name.clone())).collect(),
+        outputs: preview.outputs,
+        global: preview.global,
+        custom: preview.custom,
+        wait_token: None,
+        tag_name: preview.tag_name,
+    };
+    // rendered twice like a running task, see `WorkerInvocation::prepare_task`
+    let rendered = render_task(&task, &context)
+        .and_then(|task| render_task(&task, &context))
+        .map_err(|err| ApiError::BadRequest(err.to_string()))?;
+    Ok(HttpResponse::Ok().json(rendered))
+}
+
+#[derive(Deserialize, Debug, Default)]
+#[serde(rename_all = "camelCase")]
+pub struct RunTaskRequest {
+    // sample outputs keyed by task name, as referenced by {{OUTPUT:<task name>.<path>}}
+    #[serde(default)]
+    outputs: HashMap<String, Value>,
+    tag: Option<String>,
+}
+
+/// Runs a single task of a stored worker against sample outputs and responds with its output, so a step can be tried
+/// out while building the worker. The task's branches aren't followed and nothing is logged
+#[post("/api/tenants/{tenant_id}/workers/{worker_id}/tasks/{react_id}/run")]
+pub async fn run_task(
+    ws_data: Data<WebServerData>,
+    path: Path<(Uuid, Uuid, String)>,
+    run: Option<Json<RunTaskRequest>>,
+    auth: Authenticated,
+) -> Result<HttpResponse, ApiError> {
+    let (tenant_id, worker_id, react_id) = path.into_inner();
+    let db = ws_data.db.as_ref().ok_or(ApiError::NoDatabase)?;
+    let definition = db
+        .filter_item::<WorkerDefinition>(Some(worker_filter(&tenant_id, &worker_id)))
+        .await?
+        .ok_or_else(|| ApiError::NotFound(String::from("Worker not found")))?;
+    let worker = xpertly_worker::Worker::from_config(&definition.config)
+        .map_err(|err| ApiError::BadRequest(err.to_string()))?;
+    let user = fetch_user(tenant_id, &auth).await?;
+    let run = run.map(Json::into_inner).unwrap_or_default();
+    let output = xpertly_worker::run_task(worker, &react_id, run.tag, run.outputs, &user, &auth.token)
+        .await
+        .map_err(|err| ApiError::BadRequest(err.to_string()))?;
+    Ok(HttpResponse::Ok().json(output))
+}
+
+/// The vocabularies the worker builder offers, taken from the types the worker parses them into
+#[get("/api/capabilities")]
+pub async fn get_capabilities() -> HttpResponse {
+    HttpResponse::Ok().json(json!({
+        "comparitors": Comparitor::ALL.iter().map(Comparitor::to_string).collect::<Vec<String>>(),
+        "filterConditions": Filter::CONDITIONS,
+        "taskTypes": Handler::TYPES,
+        "variableTypes": xpertly_worker::VARIABLE_TYPES,
+    }))
+}
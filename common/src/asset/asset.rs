@@ -266,6 +266,30 @@ impl Assets {
         }
     }
 
+    /// Combines asset collections from different sources, e.g. tags and directly selected assets. Objects are merged per
+    /// tag without duplicates (see `Objects::merge`) and schema items already present are skipped
+    pub fn merge(&mut self, other: Assets) {
+        for item in other.schema.unwrap_or_default() {
+            let schema = self.schema.get_or_insert_with(Vec::new);
+            if !schema
+                .iter()
+                .any(|existing| existing.vendor == item.vendor && existing.asset_type == item.asset_type)
+            {
+                schema.push(item);
+            }
+        }
+        for (tag, objects) in other.objects.unwrap_or_default() {
+            self.objects
+                .get_or_insert_with(HashMap::new)
+                .entry(tag)
+                .or_insert(Objects {
+                    assets: None,
+                    devices: None,
+                })
+                .merge(objects);
+        }
+    }
+
     pub fn new() -> Assets {
         Assets {
             schema: Some(Vec::<SchemaItem>::new()),
@@ -26,6 +26,28 @@ impl Objects {
         }
         self.devices.as_mut().unwrap().push(device);
     }
+
+    /// Adds the assets and devices from `other` that aren't already present, matched by asset/device ID
+    pub fn merge(&mut self, other: Objects) {
+        for asset in other.assets.unwrap_or_default() {
+            let exists = self
+                .assets
+                .as_ref()
+                .map_or(false, |assets| assets.iter().any(|existing| existing.asset_id == asset.asset_id));
+            if !exists {
+                self.add_asset(asset);
+            }
+        }
+        for device in other.devices.unwrap_or_default() {
+            let exists = self
+                .devices
+                .as_ref()
+                .map_or(false, |devices| devices.iter().any(|existing| existing.device_id == device.device_id));
+            if !exists {
+                self.add_device(device);
+            }
+        }
+    }
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
@@ -41,3 +63,106 @@ pub enum Object {
     Asset(Asset),
     Device(Device),
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+    use std::collections::HashMap;
+
+    fn asset(asset_id: &str, name: &str) -> Asset {
+        Asset {
+            id: None,
+            tenant_id: String::from("tenant_id"),
+            asset_id: String::from(asset_id),
+            integration_id: String::from("integration_id"),
+            integration_type: String::from("meraki"),
+            vendor_identifier: String::from("N_1"),
+            asset_type: String::from("network"),
+            attributes: json!({"name": name}),
+        }
+    }
+
+    fn device(device_id: &str) -> Device {
+        Device {
+            id: None,
+            tenant_id: String::from("tenant_id"),
+            device_id: String::from(device_id),
+            device_serial: format!("{}_serial", device_id),
+            device_model: String::from("MX68"),
+            integration_id: String::from("integration_id"),
+            integration_type: String::from("meraki"),
+            attributes: json!({"deviceType": "appliance"}),
+        }
+    }
+
+    fn asset_ids(objects: &Objects) -> Vec<&str> {
+        objects.assets.iter().flatten().map(|asset| asset.asset_id.as_str()).collect()
+    }
+
+    fn device_ids(objects: &Objects) -> Vec<&str> {
+        objects.devices.iter().flatten().map(|device| device.device_id.as_str()).collect()
+    }
+
+    #[test]
+    fn test_objects_merge() {
+        let mut objects = Objects {
+            assets: Some(vec![asset("a", "office")]),
+            devices: None,
+        };
+        // `a` is already present, so the copy from `other` is dropped rather than replacing it, and `b` is only added
+        // once even though `other` has it twice
+        objects.merge(Objects {
+            assets: Some(vec![asset("a", "renamed"), asset("b", "warehouse"), asset("b", "warehouse")]),
+            devices: Some(vec![device("d"), device("d")]),
+        });
+        assert_eq!(asset_ids(&objects), vec!["a", "b"]);
+        assert_eq!(objects.assets.as_ref().unwrap()[0].attributes["name"], "office");
+        assert_eq!(device_ids(&objects), vec!["d"]);
+
+        // merging nothing leaves them as they were
+        objects.merge(Objects {
+            assets: None,
+            devices: None,
+        });
+        assert_eq!(asset_ids(&objects), vec!["a", "b"]);
+        assert_eq!(device_ids(&objects), vec!["d"]);
+    }
+
+    #[test]
+    fn test_assets_merge() {
+        let schema_item = |asset_type: &str| SchemaItem {
+            vendor: String::from("meraki"),
+            asset_type: String::from(asset_type),
+        };
+        let mut assets = Assets {
+            schema: Some(vec![schema_item("network")]),
+            objects: None,
+        };
+        assets.add_object("office", Object::Asset(asset("a", "office")));
+        assets.add_object("office", Object::Device(device("d")));
+
+        let mut other = Assets {
+            schema: Some(vec![schema_item("network"), schema_item("appliance")]),
+            objects: Some(HashMap::new()),
+        };
+        other.add_object("office", Object::Asset(asset("a", "office")));
+        other.add_object("office", Object::Device(device("e")));
+        other.add_object("office", Object::Device(device("e")));
+        other.add_object("warehouse", Object::Asset(asset("a", "office")));
+        assets.merge(other);
+
+        // objects are unioned per tag, an ID present under one tag doesn't stop it being added under another
+        let objects = assets.objects.as_ref().unwrap();
+        assert_eq!(asset_ids(&objects["office"]), vec!["a"]);
+        assert_eq!(device_ids(&objects["office"]), vec!["d", "e"]);
+        assert_eq!(asset_ids(&objects["warehouse"]), vec!["a"]);
+        let schema = assets
+            .schema
+            .unwrap()
+            .into_iter()
+            .map(|item| item.asset_type)
+            .collect::<Vec<String>>();
+        assert_eq!(schema, vec!["network", "appliance"]);
+    }
+}
@@ -20,6 +20,9 @@ impl Serialize for AnsibleIntegration {
         seq.serialize_entry("ansibleHostname", &self.ansible_hostname)?;
         seq.serialize_entry("username", &self.username)?;
         seq.serialize_entry("password", &self.password)?;
+        if let Some(user_agent) = &self.user_agent {
+            seq.serialize_entry("userAgent", user_agent)?;
+        }
         if let Some(id) = &self.id {
             seq.serialize_entry("_id", id)?;
         }
@@ -51,6 +54,7 @@ impl<'de> Visitor<'de> for AnsibleIntegrationVisitor {
         A: MapAccess<'de>,
     {
         let mut id: Option<ObjectId> = None;
+        let mut user_agent: Option<String> = None;
         let mut pk: Option<String> = None;
         let mut sk: Option<String> = None;
         let mut ansible_hostname: Option<String> = None;
@@ -73,6 +77,8 @@ impl<'de> Visitor<'de> for AnsibleIntegrationVisitor {
                 username = Some(map.next_value()?);
             } else if k == "password" {
                 password = Some(map.next_value()?);
+            } else if k == "userAgent" {
+                user_agent = map.next_value()?;
             } else if k == "tenantId" {
                 pk = Some(map.next_value()?);
             } else if k == "integrationId" {
@@ -108,6 +114,7 @@ impl<'de> Visitor<'de> for AnsibleIntegrationVisitor {
                 ansible_hostname: ansible_hostname.unwrap(),
                 username: username.unwrap(),
                 password: password.unwrap(),
+                user_agent,
             })
         } else if let Some(integration_id) = integration_id {
             if let Some(integration_type) = integration_type {
@@ -119,6 +126,7 @@ impl<'de> Visitor<'de> for AnsibleIntegrationVisitor {
                     ansible_hostname: ansible_hostname.unwrap(),
                     username: username.unwrap(),
                     password: password.unwrap(),
+                    user_agent,
                 })
             } else {
                 Err(serde::de::Error::custom("-- Missing integrationType -- "))
@@ -138,6 +146,7 @@ impl Display for AnsibleIntegration {
             "ansibleHostname": self.ansible_hostname,
             "username": self.username,
             "password": self.password,
+            "userAgent": self.user_agent,
         })
     }
 }
\ No newline at end of file
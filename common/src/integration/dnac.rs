@@ -21,6 +21,9 @@ impl Serialize for DnacIntegration {
         seq.serialize_entry("port", &self.port)?;
         seq.serialize_entry("username", &self.username)?;
         seq.serialize_entry("password", &self.password)?;
+        if let Some(user_agent) = &self.user_agent {
+            seq.serialize_entry("userAgent", user_agent)?;
+        }
         if let Some(id) = &self.id {
             seq.serialize_entry("_id", id)?;
         }
@@ -52,6 +55,7 @@ impl<'de> Visitor<'de> for DnacIntegrationVisitor {
         A: MapAccess<'de>,
     {
         let mut id: Option<ObjectId> = None;
+        let mut user_agent: Option<String> = None;
         let mut pk: Option<String> = None;
         let mut sk: Option<String> = None;
         let mut dnac_hostname: Option<String> = None;
@@ -77,6 +81,8 @@ impl<'de> Visitor<'de> for DnacIntegrationVisitor {
                 username = Some(map.next_value()?);
             } else if k == "password" {
                 password = Some(map.next_value()?);
+            } else if k == "userAgent" {
+                user_agent = map.next_value()?;
             } else if k == "tenantId" {
                 pk = Some(map.next_value()?);
             } else if k == "integrationId" {
@@ -109,6 +115,7 @@ impl<'de> Visitor<'de> for DnacIntegrationVisitor {
                 port: port.unwrap(),
                 username: username.unwrap(),
                 password: password.unwrap(),
+                user_agent,
             })
         } else if let Some(integration_id) = integration_id {
             if let Some(integration_type) = integration_type {
@@ -121,6 +128,7 @@ impl<'de> Visitor<'de> for DnacIntegrationVisitor {
                     port: port.unwrap(),
                     username: username.unwrap(),
                     password: password.unwrap(),
+                    user_agent,
                 })
             } else {
                 Err(serde::de::Error::custom("-- Missing integrationType -- "))
@@ -141,6 +149,7 @@ impl Display for DnacIntegration {
             "port": self.port,
             "username": self.username,
             "password": self.password,
+            "userAgent": self.user_agent,
         })
     }
 }
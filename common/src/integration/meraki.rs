@@ -19,6 +19,12 @@ impl Serialize for MerakiIntegration {
 
         seq.serialize_entry("apiKey", &self.api_key)?;
         seq.serialize_entry("organization", &self.organization)?;
+        if let Some(base_url) = &self.base_url {
+            seq.serialize_entry("baseUrl", base_url)?;
+        }
+        if let Some(user_agent) = &self.user_agent {
+            seq.serialize_entry("userAgent", user_agent)?;
+        }
         if let Some(id) = &self.id {
             seq.serialize_entry("_id", id)?;
         }
@@ -50,10 +56,12 @@ impl<'de> Visitor<'de> for MerakiIntegrationVisitor {
         A: MapAccess<'de>,
     {
         let mut id: Option<ObjectId> = None;
+        let mut user_agent: Option<String> = None;
         let mut pk: Option<String> = None;
         let mut sk: Option<String> = None;
         let mut api_key: Option<String> = None;
         let mut organization: Option<String> = None;
+        let mut base_url: Option<String> = None;
         while let Some(ref k) = map.next_key::<String>()? {
             match k.as_str() {
                 "PK" => {
@@ -68,6 +76,12 @@ impl<'de> Visitor<'de> for MerakiIntegrationVisitor {
                 "organization" => {
                     organization = Some(map.next_value()?);
                 },
+                "baseUrl" => {
+                    base_url = map.next_value()?;
+                },
+                "userAgent" => {
+                    user_agent = map.next_value()?;
+                },
                 "_id" => {
                     id = Some(map.next_value()?);
                 },
@@ -93,6 +107,8 @@ impl<'de> Visitor<'de> for MerakiIntegrationVisitor {
             integration_id: sk_splits.get(2).unwrap().to_string(),
             api_key: api_key.unwrap(),
             organization: organization.unwrap(),
+            base_url,
+            user_agent,
         })
     }
 }
@@ -105,6 +121,8 @@ impl Display for MerakiIntegration {
             "tenantId": self.tenant_id,
             "apiKey": self.api_key,
             "organization": self.organization,
+            "baseUrl": self.base_url,
+            "userAgent": self.user_agent,
         })
     }
 }
\ No newline at end of file
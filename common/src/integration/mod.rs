@@ -2,17 +2,22 @@ use anyhow::{anyhow, Result};
 use mongo_api::MongoDbModel;
 use mongo_derive::MongoModel;
 use mongodb::bson::oid::ObjectId;
-use serde::{Deserialize, Serialize};
+use once_cell::sync::Lazy;
+use serde::{de, Deserialize, Deserializer, Serialize};
 use serde_json::Value;
+use std::fmt;
 use crate::Display;
 
 pub mod ansible;
 pub mod dnac;
 pub mod meraki;
+pub mod pagerduty;
+pub mod prometheus;
 pub mod splunk;
 pub mod viptela;
 
-#[derive(Serialize, Deserialize, Debug, Clone, MongoModel)]
+// integrations are deserialized as their vendor's, see `Integration::from_value`
+#[derive(Serialize, Debug, Clone, MongoModel)]
 #[serde(untagged)]
 pub enum Integration {
     Meraki(MerakiIntegration),
@@ -20,36 +25,188 @@ pub enum Integration {
     Splunk(SplunkIntegration),
     Dnac(DnacIntegration),
     Viptela(ViptelaIntegration),
+    PagerDuty(PagerDutyIntegration),
+    Prometheus(PrometheusIntegration),
 }
 
-impl Integration {
-    pub fn new(integration: serde_json::Value) -> Result<Self> {
-        let integration_type = integration["integrationType"].as_str();
-        if let Some(vendor) = integration_type {
-            match vendor {
-                "meraki" => Ok(Integration::Meraki(
-                    serde_json::from_value(integration).unwrap(),
-                )),
-                "ansible" => Ok(Integration::Ansible(
-                    serde_json::from_value(integration).unwrap(),
-                )),
-                "splunk" => Ok(Integration::Splunk(
-                    serde_json::from_value(integration).unwrap(),
-                )),
-                "dnac" => Ok(Integration::Dnac(
-                    serde_json::from_value(integration).unwrap(),
-                )),
-                "viptela" => Ok(Integration::Viptela(
-                    serde_json::from_value(integration).unwrap(),
-                )),
-                other => Err(anyhow!("expected a valid vendor, got {}", other)),
-            }
+/// Vendors this deployment allows integrations for, read from the comma separated `ENABLED_VENDORS` env var
+/// (e.g. `meraki,splunk`). Every vendor is enabled if it isn't set.
+#[derive(Debug, Clone)]
+pub struct EnabledVendors {
+    vendors: Option<Vec<String>>,
+}
+
+impl EnabledVendors {
+    pub fn from_env() -> Self {
+        let vendors = std::env::var("ENABLED_VENDORS").ok().map(|vendors| {
+            vendors
+                .split(',')
+                .map(|vendor| vendor.trim().to_lowercase())
+                .filter(|vendor| !vendor.is_empty())
+                .collect()
+        });
+        EnabledVendors { vendors }
+    }
+
+    pub fn is_enabled(&self, vendor: &str) -> bool {
+        match &self.vendors {
+            Some(vendors) => vendors.iter().any(|enabled| enabled.eq_ignore_ascii_case(vendor)),
+            None => true,
+        }
+    }
+
+    pub fn check(&self, vendor: &str) -> Result<()> {
+        if self.is_enabled(vendor) {
+            Ok(())
         } else {
-            Err(anyhow!("no vendor found"))
+            Err(anyhow!("{} integrations are not enabled on this deployment", vendor))
+        }
+    }
+}
+
+pub static ENABLED_VENDORS: Lazy<EnabledVendors> = Lazy::new(EnabledVendors::from_env);
+
+/// Vendors there are integrations for, as given in an integration's `integrationType` or SK
+pub const SUPPORTED_VENDORS: &[&str] = &["meraki", "ansible", "splunk", "dnac", "viptela", "pagerduty", "prometheus"];
+
+/// Why an integration couldn't be read
+#[derive(Debug, Clone, PartialEq)]
+pub enum IntegrationError {
+    /// neither an `integrationType` nor an SK of the form `integration#<vendor>#<id>`
+    MissingVendor,
+    /// a vendor that isn't one of `SUPPORTED_VENDORS`
+    UnknownVendor(String),
+    /// a vendor left out of `ENABLED_VENDORS`
+    VendorDisabled(String),
+    /// fields that don't make up an integration for the vendor
+    Invalid { vendor: String, message: String },
+}
+
+impl fmt::Display for IntegrationError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            IntegrationError::MissingVendor => write!(
+                f,
+                "integration has no vendor, expected an integrationType or an SK of integration#<vendor>#<id>"
+            ),
+            IntegrationError::UnknownVendor(vendor) => write!(
+                f,
+                "unknown integration vendor {}, expected one of {}",
+                vendor,
+                SUPPORTED_VENDORS.join(", ")
+            ),
+            IntegrationError::VendorDisabled(vendor) => {
+                write!(f, "{} integrations are not enabled on this deployment", vendor)
+            }
+            IntegrationError::Invalid { vendor, message } => write!(f, "invalid {} integration: {}", vendor, message),
         }
     }
 }
 
+impl std::error::Error for IntegrationError {}
+
+// the vendor an integration is for, from its integrationType or else its SK
+fn vendor_of(integration: &Value) -> Option<String> {
+    if let Some(vendor) = integration["integrationType"].as_str() {
+        return Some(vendor.to_string());
+    }
+    let sk = integration["SK"].as_str()?;
+    match sk.split('#').collect::<Vec<&str>>()[..] {
+        ["integration", vendor, _] => Some(vendor.to_string()),
+        _ => None,
+    }
+}
+
+impl Integration {
+    /// Reads an integration of one of the deployment's enabled vendors, see `ENABLED_VENDORS`
+    pub fn new(integration: serde_json::Value) -> Result<Self, IntegrationError> {
+        let vendor = vendor_of(&integration).ok_or(IntegrationError::MissingVendor)?;
+        if SUPPORTED_VENDORS.contains(&vendor.as_str()) && !ENABLED_VENDORS.is_enabled(&vendor) {
+            return Err(IntegrationError::VendorDisabled(vendor));
+        }
+        Integration::from_value(integration)
+    }
+
+    /// Reads an integration as its vendor's, whether or not the vendor is enabled. This is how integrations are
+    /// deserialized, so a bad one fails naming its vendor and what's wrong with it
+    pub fn from_value(integration: serde_json::Value) -> Result<Self, IntegrationError> {
+        let vendor = vendor_of(&integration).ok_or(IntegrationError::MissingVendor)?;
+        let invalid = |err: serde_json::Error| IntegrationError::Invalid {
+            vendor: vendor.clone(),
+            message: err.to_string(),
+        };
+        match vendor.as_str() {
+            "meraki" => serde_json::from_value(integration).map(Integration::Meraki).map_err(invalid),
+            "ansible" => serde_json::from_value(integration).map(Integration::Ansible).map_err(invalid),
+            "splunk" => serde_json::from_value(integration).map(Integration::Splunk).map_err(invalid),
+            "dnac" => serde_json::from_value(integration).map(Integration::Dnac).map_err(invalid),
+            "viptela" => serde_json::from_value(integration).map(Integration::Viptela).map_err(invalid),
+            "pagerduty" => serde_json::from_value(integration).map(Integration::PagerDuty).map_err(invalid),
+            "prometheus" => serde_json::from_value(integration).map(Integration::Prometheus).map_err(invalid),
+            other => Err(IntegrationError::UnknownVendor(other.to_string())),
+        }
+    }
+
+    /// Base URL of the vendor API this integration talks to, for vendors with regional or sovereign APIs.
+    ///
+    /// Endpoint target URLs using the `base-url` placeholder host are rewritten against it, so the same worker can
+    /// run against different regions by swapping the integration. The placeholder's scheme and host are replaced
+    /// with the base URL's and the base URL's path is prepended to the target's path, keeping the query. With a
+    /// base URL of `https://api.meraki.ca/api/v1`, `https://base-url/organizations?perPage=10` becomes
+    /// `https://api.meraki.ca/api/v1/organizations?perPage=10`.
+    ///
+    /// Self-hosted vendors already have their hostname in the integration, which can be used in target URLs as a
+    /// variable instead (e.g. `https://{{dnacHostname}}/dna/intent/api/v1/network-device`).
+    pub fn base_url(&self) -> Option<&str> {
+        match self {
+            Integration::Meraki(integration) => integration.base_url.as_deref(),
+            Integration::PagerDuty(integration) => integration.base_url.as_deref(),
+            _ => None,
+        }
+    }
+
+    /// User-Agent sent with requests made through this integration, for vendor APIs that rate limit or block requests
+    /// without a recognisable one.
+    ///
+    /// Precedence, most specific first: a `User-Agent` header set on the endpoint task, the integration's, the
+    /// worker's `userAgent`, then the `HTTP_USER_AGENT` env var every client is built with (see
+    /// `client::build_client` in the worker).
+    pub fn user_agent(&self) -> Option<&str> {
+        match self {
+            Integration::Meraki(integration) => integration.user_agent.as_deref(),
+            Integration::Ansible(integration) => integration.user_agent.as_deref(),
+            Integration::Splunk(integration) => integration.user_agent.as_deref(),
+            Integration::Dnac(integration) => integration.user_agent.as_deref(),
+            Integration::Viptela(integration) => integration.user_agent.as_deref(),
+            Integration::PagerDuty(integration) => integration.user_agent.as_deref(),
+            Integration::Prometheus(integration) => integration.user_agent.as_deref(),
+        }
+    }
+
+    // database ID, only set on integrations read back from the database
+    pub fn id(&self) -> Option<&ObjectId> {
+        match self {
+            Integration::Meraki(integration) => integration.id.as_ref(),
+            Integration::Ansible(integration) => integration.id.as_ref(),
+            Integration::Splunk(integration) => integration.id.as_ref(),
+            Integration::Dnac(integration) => integration.id.as_ref(),
+            Integration::Viptela(integration) => integration.id.as_ref(),
+            Integration::PagerDuty(integration) => integration.id.as_ref(),
+            Integration::Prometheus(integration) => integration.id.as_ref(),
+        }
+    }
+}
+
+impl<'de> Deserialize<'de> for Integration {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        let integration = Value::deserialize(deserializer)?;
+        Integration::from_value(integration).map_err(de::Error::custom)
+    }
+}
+
 impl Display for Integration {
     fn display(&self) -> Value {
         match self {
@@ -58,6 +215,8 @@ impl Display for Integration {
             Integration::Splunk(integration) => integration.display(),
             Integration::Dnac(integration) => integration.display(),
             Integration::Viptela(integration) => integration.display(),
+            Integration::PagerDuty(integration) => integration.display(),
+            Integration::Prometheus(integration) => integration.display(),
         }
     }
 }
@@ -70,6 +229,10 @@ pub struct MerakiIntegration {
     pub integration_id: String,
     pub api_key: String,
     pub organization: String,
+    // regional API, e.g. https://api.meraki.ca/api/v1, see `Integration::base_url`
+    pub base_url: Option<String>,
+    // sent as the User-Agent of this integration's requests, see `Integration::user_agent`
+    pub user_agent: Option<String>,
 }
 
 #[derive(Debug, Clone)]
@@ -81,6 +244,8 @@ pub struct AnsibleIntegration {
     pub ansible_hostname: String,
     pub username: String,
     pub password: String,
+    // sent as the User-Agent of this integration's requests, see `Integration::user_agent`
+    pub user_agent: Option<String>,
 }
 
 #[derive(Debug, Clone)]
@@ -92,6 +257,8 @@ pub struct SplunkIntegration {
     pub hostname: String,
     pub port: String,
     pub hec_token: String,
+    // sent as the User-Agent of this integration's requests, see `Integration::user_agent`
+    pub user_agent: Option<String>,
 }
 
 #[derive(Debug, Clone)]
@@ -104,6 +271,8 @@ pub struct DnacIntegration {
     pub port: String,
     pub username: String,
     pub password: String,
+    // sent as the User-Agent of this integration's requests, see `Integration::user_agent`
+    pub user_agent: Option<String>,
 }
 
 #[derive(Debug, Clone)]
@@ -114,5 +283,35 @@ pub struct ViptelaIntegration {
     pub integration_id: String,
     pub v_manage_hostname: String,
     pub username: String,
-    pub password: String
+    pub password: String,
+    // sent as the User-Agent of this integration's requests, see `Integration::user_agent`
+    pub user_agent: Option<String>,
+}
+
+#[derive(Debug, Clone)]
+pub struct PagerDutyIntegration {
+    pub id: Option<ObjectId>,
+    pub tenant_id: String,
+    pub integration_type: String,
+    pub integration_id: String,
+    pub routing_key: String,
+    // regional API, e.g. https://api.eu.pagerduty.com, see `Integration::base_url`
+    pub base_url: Option<String>,
+    // sent as the User-Agent of this integration's requests, see `Integration::user_agent`
+    pub user_agent: Option<String>,
+}
+
+#[derive(Debug, Clone)]
+pub struct PrometheusIntegration {
+    pub id: Option<ObjectId>,
+    pub tenant_id: String,
+    pub integration_type: String,
+    pub integration_id: String,
+    // scheme and host of the Prometheus server, e.g. https://prometheus.example.com:9090. Query endpoints are
+    // targeted with it as a variable, e.g. {{prometheusHostname}}/api/v1/query
+    pub prometheus_hostname: String,
+    // servers behind an authenticating proxy take a bearer token, unauthenticated servers don't need one
+    pub bearer_token: Option<String>,
+    // sent as the User-Agent of this integration's requests, see `Integration::user_agent`
+    pub user_agent: Option<String>,
 }
@@ -0,0 +1,143 @@
+use crate::Display;
+
+use super::PagerDutyIntegration;
+use mongodb::bson::oid::ObjectId;
+use serde::{de::MapAccess, de::Visitor, ser::SerializeMap, Deserialize, Serialize};
+use serde_json::{json, Value};
+
+struct PagerDutyIntegrationVisitor;
+
+impl Serialize for PagerDutyIntegration {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: serde::Serializer,
+    {
+        let mut seq = serializer.serialize_map(Some(4))?;
+        seq.serialize_entry("PK", &self.tenant_id)?;
+        let sk = format!("integration#{}#{}", &self.integration_type.to_string(), &self.integration_id.to_string());
+        seq.serialize_entry("SK", &sk)?;
+
+        seq.serialize_entry("routingKey", &self.routing_key)?;
+        if let Some(base_url) = &self.base_url {
+            seq.serialize_entry("baseUrl", base_url)?;
+        }
+        if let Some(user_agent) = &self.user_agent {
+            seq.serialize_entry("userAgent", user_agent)?;
+        }
+        if let Some(id) = &self.id {
+            seq.serialize_entry("_id", id)?;
+        }
+        seq.end()
+    }
+}
+
+impl<'de> Deserialize<'de> for PagerDutyIntegration {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: serde::Deserializer<'de>,
+    {
+        deserializer.deserialize_map(PagerDutyIntegrationVisitor)
+    }
+}
+
+impl<'de> Visitor<'de> for PagerDutyIntegrationVisitor {
+    type Value = PagerDutyIntegration;
+
+    fn expecting(&self, formatter: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(
+            formatter,
+            "a map with keys 'PK', 'SK', 'routingKey'"
+        )
+    }
+
+    fn visit_map<A>(self, mut map: A) -> Result<Self::Value, A::Error>
+    where
+        A: MapAccess<'de>,
+    {
+        let mut id: Option<ObjectId> = None;
+        let mut user_agent: Option<String> = None;
+        let mut pk: Option<String> = None;
+        let mut sk: Option<String> = None;
+        let mut routing_key: Option<String> = None;
+        let mut base_url: Option<String> = None;
+
+        let mut integration_id: Option<String> = None;
+        let mut integration_type: Option<String> = None;
+
+        while let Some(ref k) = map.next_key::<String>()? {
+            if k == "_id" {
+                id = Some(map.next_value()?);
+            } else if k == "PK" {
+                pk = Some(map.next_value()?);
+            } else if k == "SK" {
+                sk = Some(map.next_value()?);
+            } else if k == "routingKey" {
+                routing_key = Some(map.next_value()?);
+            } else if k == "baseUrl" {
+                base_url = map.next_value()?;
+            } else if k == "userAgent" {
+                user_agent = map.next_value()?;
+            } else if k == "tenantId" {
+                pk = Some(map.next_value()?);
+            } else if k == "integrationId" {
+                integration_id = Some(map.next_value()?);
+            } else if k == "integrationType" {
+                integration_type = Some(map.next_value()?);
+            } else {
+                return Err(serde::de::Error::custom(&format!("Invalid key: {}", k)));
+            }
+        }
+
+        if pk.is_none() || routing_key.is_none() {
+            return Err(serde::de::Error::custom("-- Missing Attributes -- "));
+        }
+
+        if sk.is_some() {
+            let sk = sk.unwrap();
+            let sk_splits = sk.split("#").collect::<Vec<&str>>();
+            if sk_splits.len() != 3 {
+                //sk string contains exact 3 properties
+                return Err(serde::de::Error::custom("-- Wrong SK attribute format -- "));
+            }
+
+            Ok(PagerDutyIntegration {
+                id: id,
+                tenant_id: pk.unwrap(),
+                integration_type: "pagerduty".to_string(),
+                integration_id: sk_splits.get(2).unwrap().to_string(),
+                routing_key: routing_key.unwrap(),
+                base_url,
+                user_agent,
+            })
+        } else if let Some(integration_id) = integration_id {
+            if let Some(integration_type) = integration_type {
+                Ok(PagerDutyIntegration {
+                    id: id,
+                    tenant_id: pk.unwrap(),
+                    integration_type: integration_type,
+                    integration_id: integration_id,
+                    routing_key: routing_key.unwrap(),
+                    base_url,
+                    user_agent,
+                })
+            } else {
+                Err(serde::de::Error::custom("-- Missing integrationType -- "))
+            }
+        } else {
+            Err(serde::de::Error::custom("-- Missing integrationId -- "))
+        }
+    }
+}
+
+impl Display for PagerDutyIntegration {
+    fn display(&self) -> Value {
+        json!({
+            "integrationId": self.integration_id,
+            "integrationType": self.integration_type,
+            "tenantId": self.tenant_id,
+            "routingKey": self.routing_key,
+            "baseUrl": self.base_url,
+            "userAgent": self.user_agent,
+        })
+    }
+}
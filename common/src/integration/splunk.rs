@@ -22,6 +22,9 @@ impl Serialize for SplunkIntegration {
         seq.serialize_entry("hostname", &self.hostname)?;
         seq.serialize_entry("port", &self.port)?;
         seq.serialize_entry("hecToken", &self.hec_token)?;
+        if let Some(user_agent) = &self.user_agent {
+            seq.serialize_entry("userAgent", user_agent)?;
+        }
         if let Some(id) = &self.id {
             seq.serialize_entry("_id", id)?;
         }
@@ -53,6 +56,7 @@ impl<'de> Visitor<'de> for SplunkIntegrationVisitor {
         A: MapAccess<'de>,
     {
         let mut id: Option<ObjectId> = None;
+        let mut user_agent: Option<String> = None;
         let mut pk: Option<String> = None;
         let mut sk: Option<String> = None;
         let mut hostname: Option<String> = None;
@@ -75,6 +79,8 @@ impl<'de> Visitor<'de> for SplunkIntegrationVisitor {
                 port = Some(map.next_value()?);
             } else if k == "hecToken" {
                 hec_token = Some(map.next_value()?);
+            } else if k == "userAgent" {
+                user_agent = map.next_value()?;
             } else if k == "tenantId" {
                 pk = Some(map.next_value()?);
             } else if k == "integrationId" {
@@ -110,6 +116,7 @@ impl<'de> Visitor<'de> for SplunkIntegrationVisitor {
                 hostname: hostname.unwrap(),
                 port: port.unwrap(),
                 hec_token: hec_token.unwrap(),
+                user_agent,
             })
         } else if let Some(integration_id) = integration_id {
             if let Some(integration_type) = integration_type {
@@ -121,6 +128,7 @@ impl<'de> Visitor<'de> for SplunkIntegrationVisitor {
                     hostname: hostname.unwrap(),
                     port: port.unwrap(),
                     hec_token: hec_token.unwrap(),
+                    user_agent,
                 })
             } else {
                 Err(serde::de::Error::custom("-- Missing integrationType -- "))
@@ -140,6 +148,7 @@ impl Display for SplunkIntegration {
             "hostname": self.hostname,
             "port": self.port,
             "hecToken": self.hec_token,
+            "userAgent": self.user_agent,
         })
     }
 }
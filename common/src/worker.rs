@@ -1,9 +1,14 @@
 use super::asset::Assets;
 use anyhow::{bail, Result};
-use chrono::{DateTime, Utc};
+use chrono::{DateTime, NaiveDate, NaiveDateTime, TimeZone, Utc};
+use mongo_api::MongoDbModel;
+use mongo_derive::MongoModel;
+use mongodb::bson::oid::ObjectId;
 use serde::{Deserialize, Serialize};
-use serde_json::Value;
+use serde_json::{Map, Value};
 use serde_with;
+use sha2::{Digest, Sha256};
+use std::cmp::Ordering;
 use std::collections::HashMap;
 use std::str::FromStr;
 use uuid::Uuid;
@@ -20,11 +25,346 @@ pub struct WorkerConfig {
     pub schedule: Option<Schedule>,
     pub description: String,
     pub tasks: Vec<TaskConfig>,
+    // react ID of the task execution starts from. If omitted, the start is the only task without a previous task
+    pub start_react_id: Option<String>,
+    // tasks run after the main graph terminates, whether it succeeded or failed
+    pub finalizer: Option<Vec<TaskConfig>>,
+    // records the request sent by each endpoint task in its output, for debugging vendor errors
+    #[serde(default)]
+    pub debug_requests: bool,
+    // value of {{tagName}} when the worker is triggered without tags. Without one, {{tagName}} renders as `undefined`
+    pub default_tag: Option<String>,
+    // User-Agent for the worker's endpoint requests, unless their integration sets one, see `Integration::user_agent`
+    pub user_agent: Option<String>,
+    // global and custom values can be overridden per tag under a `$tags` key, mapping tags to the entries they override
     pub global: Option<Value>,
     pub custom: Option<Value>,
     pub schema_id: Option<String>,
 }
 
+/// A worker config stored in the database, so it can be run by ID instead of being sent with every trigger
+#[derive(Serialize, Deserialize, Debug, Clone, MongoModel)]
+pub struct WorkerDefinition {
+    #[serde(rename = "_id", skip_serializing_if = "Option::is_none")]
+    pub id: Option<ObjectId>,
+    #[serde(rename = "PK")]
+    pub tenant_id: String,
+    #[serde(rename = "SK")]
+    pub sort_key: String,
+    pub config: WorkerConfig,
+}
+
+impl WorkerDefinition {
+    pub fn new(config: WorkerConfig) -> Self {
+        WorkerDefinition {
+            id: None,
+            tenant_id: config.tenant_id.to_string(),
+            sort_key: WorkerDefinition::sort_key(&config.id),
+            config,
+        }
+    }
+
+    pub fn sort_key(worker_id: &Uuid) -> String {
+        format!("worker#{}", worker_id)
+    }
+}
+
+/// The config and trigger parameters an execution was started with, so it can be retried without resubmitting them
+#[derive(Serialize, Deserialize, Debug, Clone, MongoModel)]
+#[serde(rename_all = "camelCase")]
+pub struct ExecutionRecord {
+    #[serde(rename = "_id", skip_serializing_if = "Option::is_none")]
+    pub id: Option<ObjectId>,
+    #[serde(rename = "PK")]
+    pub tenant_id: String,
+    #[serde(rename = "SK")]
+    pub sort_key: String,
+    pub execution_id: Uuid,
+    pub worker: WorkerConfig,
+    pub tags: Vec<String>,
+    pub started_at: DateTime<Utc>,
+    // the execution this one retried, if any
+    pub retry_of: Option<Uuid>,
+    // the run's `WorkerConfig::content_hash`, so runs of the same config with the same tags can be found. Records
+    // written before hashes were recorded have none
+    #[serde(default)]
+    pub content_hash: Option<String>,
+}
+
+impl ExecutionRecord {
+    pub fn new(execution_id: Uuid, worker: WorkerConfig, tags: Vec<String>, retry_of: Option<Uuid>) -> Self {
+        ExecutionRecord {
+            id: None,
+            tenant_id: worker.tenant_id.to_string(),
+            sort_key: ExecutionRecord::sort_key(&execution_id),
+            execution_id,
+            content_hash: Some(worker.content_hash(&tags)),
+            worker,
+            tags,
+            started_at: Utc::now(),
+            retry_of,
+        }
+    }
+
+    pub fn sort_key(execution_id: &Uuid) -> String {
+        format!("execution#{}", execution_id)
+    }
+}
+
+impl WorkerConfig {
+    // react ID of the task execution starts from, either the one designated by `start_react_id` or the only task
+    // that has no previous task
+    pub fn start_task(&self) -> Result<String> {
+        if let Some(start) = &self.start_react_id {
+            if !self.tasks.iter().any(|task| &task.react_id == start) {
+                bail!("Start task {} not found in worker", start);
+            }
+            return Ok(start.clone());
+        }
+
+        let starts = self
+            .tasks
+            .iter()
+            .filter(|task| match &task.prev {
+                Some(prev) => prev.true_branch.is_none() && prev.false_branch.is_none(),
+                None => true,
+            })
+            .map(|task| task.react_id.clone())
+            .collect::<Vec<String>>();
+        match starts.len() {
+            1 => Ok(starts[0].clone()),
+            0 => bail!("Worker has no start task, every task has a previous task"),
+            _ => bail!(
+                "Worker has multiple possible start tasks ({}), set startReactId to choose one",
+                starts.join(", ")
+            ),
+        }
+    }
+
+    // fails naming the first react ID shared by more than one task, including tasks nested in loops and the finalizer.
+    // Tasks are looked up by react ID, so a duplicate would silently replace the task before it
+    pub fn check_unique_react_ids(&self) -> Result<()> {
+        fn check<'a>(tasks: &'a [TaskConfig], seen: &mut Vec<&'a str>) -> Result<()> {
+            for task in tasks {
+                if seen.contains(&task.react_id.as_str()) {
+                    bail!("Duplicate react ID {} in worker", task.react_id);
+                }
+                seen.push(&task.react_id);
+                if let TaskFields::Loop(loop_fields) = &task.fields {
+                    check(&loop_fields.tasks, seen)?;
+                }
+            }
+            Ok(())
+        }
+
+        let mut seen = vec![];
+        check(&self.tasks, &mut seen)?;
+        if let Some(finalizer) = &self.finalizer {
+            check(finalizer, &mut seen)?;
+        }
+        Ok(())
+    }
+
+    // fails naming the first fan-out without a true branch to run alongside its parallel branches, that fans out to
+    // or merges at a task that isn't in the worker, that waits for a callback or that's inside a loop. A resumed task
+    // only carries on down one branch, so a fan-out can't wait, and loops run their tasks one after the other
+    pub fn check_fan_outs(&self) -> Result<()> {
+        fn check_loop(loop_task: &TaskConfig, tasks: &[TaskConfig]) -> Result<()> {
+            for task in tasks {
+                if task.next.as_ref().map_or(false, |next| !next.parallel.is_empty()) {
+                    bail!(
+                        "Task {} fans out inside loop {}, only tasks outside loops can fan out",
+                        task.react_id,
+                        loop_task.react_id
+                    );
+                }
+                if let TaskFields::Loop(loop_fields) = &task.fields {
+                    check_loop(task, &loop_fields.tasks)?;
+                }
+            }
+            Ok(())
+        }
+
+        for task in &self.tasks {
+            if let TaskFields::Loop(loop_fields) = &task.fields {
+                check_loop(task, &loop_fields.tasks)?;
+            }
+            let next = match &task.next {
+                Some(next) if !next.parallel.is_empty() => next,
+                _ => continue,
+            };
+            if next.true_branch.is_none() {
+                bail!("Task {} fans out but has no true branch", task.react_id);
+            }
+            if task.needs_to_wait {
+                bail!("Task {} fans out, so it can't wait for a callback", task.react_id);
+            }
+            for target in next.parallel.iter().chain(next.merge.iter()) {
+                if !self.tasks.iter().any(|other| &other.react_id == target) {
+                    bail!("Task {} fans out to {}, which isn't in the worker", task.react_id, target);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    // fails naming the first task that waits for a callback inside a loop nested in another loop. A suspended loop
+    // records where it stopped in its cursor, but the loop around it only records which of its own tasks was waiting,
+    // so the nested loop's progress would be lost and the run couldn't be resumed where it stopped
+    pub fn check_nested_loop_waits(&self) -> Result<()> {
+        fn first_wait(tasks: &[TaskConfig]) -> Option<&TaskConfig> {
+            tasks.iter().find_map(|task| match &task.fields {
+                TaskFields::Loop(loop_fields) => first_wait(&loop_fields.tasks),
+                _ => Some(task).filter(|task| task.needs_to_wait),
+            })
+        }
+
+        for task in &self.tasks {
+            let loop_fields = match &task.fields {
+                TaskFields::Loop(loop_fields) => loop_fields,
+                _ => continue,
+            };
+            for inner in &loop_fields.tasks {
+                if let TaskFields::Loop(nested) = &inner.fields {
+                    if let Some(waiting) = first_wait(&nested.tasks) {
+                        bail!(
+                            "Task {} waits for a callback inside loop {}, which is nested in loop {}. Only tasks \
+                             directly inside a loop can wait",
+                            waiting.react_id,
+                            inner.react_id,
+                            task.react_id
+                        );
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+
+    // fails naming the first output name shared by more than one task, including tasks nested in loops. Outputs are
+    // referenced by name, so a duplicate would leave {{OUTPUT:...}} variables resolving to either task. The
+    // finalizer's outputs can't be referenced by name, so its tasks aren't checked
+    pub fn check_unique_output_names(&self) -> Result<()> {
+        fn check<'a>(tasks: &'a [TaskConfig], seen: &mut HashMap<&'a str, &'a str>) -> Result<()> {
+            for task in tasks {
+                if let Some(name) = task.output_name() {
+                    if let Some(other) = seen.insert(name, &task.react_id) {
+                        bail!(
+                            "Tasks {} and {} are both named {}, give one an outputAlias so their outputs can be told apart",
+                            other,
+                            task.react_id,
+                            name
+                        );
+                    }
+                }
+                if let TaskFields::Loop(loop_fields) = &task.fields {
+                    check(&loop_fields.tasks, seen)?;
+                }
+            }
+            Ok(())
+        }
+
+        check(&self.tasks, &mut HashMap::new())
+    }
+
+    // vendors of every integration used by the worker's tasks, including those nested in loops and the finalizer
+    pub fn integration_vendors(&self) -> Vec<String> {
+        fn collect(tasks: &[TaskConfig], vendors: &mut Vec<String>) {
+            for task in tasks {
+                if let (Some(vendor), Some(_)) = (&task.vendor, task.integration_id) {
+                    if !vendors.contains(vendor) {
+                        vendors.push(vendor.clone());
+                    }
+                }
+                if let TaskFields::Loop(loop_fields) = &task.fields {
+                    collect(&loop_fields.tasks, vendors);
+                }
+            }
+        }
+
+        let mut vendors = vec![];
+        collect(&self.tasks, &mut vendors);
+        if let Some(finalizer) = &self.finalizer {
+            collect(finalizer, &mut vendors);
+        }
+        vendors
+    }
+
+    /// Hex SHA-256 of what the worker does when run with `tags`, the same for configs that only differ in the order of
+    /// their graph's tasks, in fields that don't affect a run (the worker's name, description, IDs and schedule, task
+    /// descriptions and canvas positions) or in the order of object keys. Finalizer tasks run in order and tags are run
+    /// in order, so their order is hashed
+    pub fn content_hash(&self, tags: &[String]) -> String {
+        const IGNORED: &[&str] = &[
+            "name",
+            "id",
+            "tenantId",
+            "type",
+            "availableInAvicenna",
+            "schedule",
+            "description",
+            "schemaId",
+        ];
+        let mut config = serde_json::to_value(self).unwrap_or_default();
+        if let Value::Object(fields) = &mut config {
+            for field in IGNORED {
+                fields.remove(*field);
+            }
+            fields.insert(String::from("tasks"), normalize_tasks(&self.tasks, true));
+            if let Some(finalizer) = &self.finalizer {
+                fields.insert(String::from("finalizer"), normalize_tasks(finalizer, false));
+            }
+            fields.insert(String::from("tags"), Value::from(tags.to_vec()));
+        }
+        let digest = Sha256::digest(sort_keys(config).to_string().as_bytes());
+        format!("{:x}", digest)
+    }
+}
+
+// tasks as they're hashed, sorted by react ID with `sort` for tasks that run as a graph. Integration IDs aren't
+// serialized so they're added back, the integration a task calls changes what it does
+fn normalize_tasks(tasks: &[TaskConfig], sort: bool) -> Value {
+    const IGNORED: &[&str] = &["description", "xPos", "yPos"];
+    let mut tasks = tasks.iter().collect::<Vec<&TaskConfig>>();
+    if sort {
+        tasks.sort_by(|a, b| a.react_id.cmp(&b.react_id));
+    }
+    let normalized = tasks
+        .into_iter()
+        .map(|task| {
+            let mut value = serde_json::to_value(task).unwrap_or_default();
+            if let Value::Object(fields) = &mut value {
+                for field in IGNORED {
+                    fields.remove(*field);
+                }
+                let integration_id = serde_json::to_value(task.integration_id).unwrap_or_default();
+                fields.insert(String::from("integrationId"), integration_id);
+                if let (TaskFields::Loop(loop_fields), Some(Value::Object(task_fields))) =
+                    (&task.fields, fields.get_mut("fields"))
+                {
+                    task_fields.insert(String::from("tasks"), normalize_tasks(&loop_fields.tasks, true));
+                }
+            }
+            value
+        })
+        .collect();
+    Value::Array(normalized)
+}
+
+// rebuilds objects with their keys inserted in order, so they serialize the same whatever order they were built in
+fn sort_keys(value: Value) -> Value {
+    match value {
+        Value::Object(fields) => {
+            let mut fields = fields.into_iter().collect::<Vec<(String, Value)>>();
+            fields.sort_by(|a, b| a.0.cmp(&b.0));
+            let sorted = fields.into_iter().map(|(key, value)| (key, sort_keys(value)));
+            Value::Object(sorted.collect::<Map<String, Value>>())
+        }
+        Value::Array(values) => Value::Array(values.into_iter().map(sort_keys).collect()),
+        value => value,
+    }
+}
+
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct ScheduledExecution {
     schedule: Schedule,
@@ -53,7 +393,18 @@ pub struct TaskConfig {
     pub x_pos: i64,
     pub y_pos: i64,
     pub needs_to_wait: bool,
+    // the task is skipped unless these conditions hold, following its true branch
+    #[serde(default)]
+    pub run_if: Option<Vec<ConditionGroup>>,
+    // for tasks that need to wait, the shape of the custom output they're resumed with
+    #[serde(default)]
+    pub output_schema: Option<OutputSchema>,
+    // name the task's output is referenced by in {{OUTPUT:...}} variables instead of its name, for tasks that share
+    // a name with another task
+    #[serde(default)]
+    pub output_alias: Option<String>,
     pub fields: TaskFields,
+    pub prev: Option<Next>,
     pub next: Option<Next>,
     pub assets: Assets,
     pub path_params_pair: Option<Vec<HashMap<String, String>>>,
@@ -63,6 +414,16 @@ pub struct TaskConfig {
     pub integration_id: Option<Uuid>,
 }
 
+impl TaskConfig {
+    /// The name the task's output is referenced by, its output alias if it has one. Unnamed tasks have none
+    pub fn output_name(&self) -> Option<&str> {
+        fn non_empty(name: &Option<String>) -> Option<&str> {
+            name.as_deref().filter(|name| !name.is_empty())
+        }
+        non_empty(&self.output_alias).or_else(|| non_empty(&self.name))
+    }
+}
+
 #[derive(Serialize, Deserialize, Debug, Clone)]
 #[serde(rename_all = "camelCase")]
 #[serde(untagged)]
@@ -71,6 +432,21 @@ pub enum TaskFields {
     Loop(LoopFields),
     Conditional(ConditionalFields),
     Filter(FilterFields),
+    // last, as the other fields wouldn't be told apart from a switch's
+    Switch(SwitchFields),
+}
+
+impl TaskFields {
+    /// The task type these fields are for, as given in a task config's `type`. Webhooks have endpoint fields too
+    pub fn task_type(&self) -> &'static str {
+        match self {
+            TaskFields::Endpoint(_) => "endpoint",
+            TaskFields::Loop(_) => "loop",
+            TaskFields::Conditional(_) => "conditional",
+            TaskFields::Filter(_) => "filter",
+            TaskFields::Switch(_) => "switch",
+        }
+    }
 }
 
 /**
@@ -83,8 +459,67 @@ pub struct EndpointFields {
     pub headers: Option<Vec<Header>>,
     pub path_params: Option<HashMap<String, String>>,
     pub query_params: Option<HashMap<String, HashMap<String, String>>>,
+    // query params that render empty or `undefined` are left out of the request, e.g. optional filters without a
+    // value. Set to send them anyway, as `?param=`
+    #[serde(default)]
+    pub send_empty_query_params: bool,
     pub body: Option<Value>,
     pub target_url: String,
+    // JSON pointer (e.g. /data) to the part of the response stored as the task's output, the whole response is stored if omitted
+    pub response_path: Option<String>,
+    #[serde(default)]
+    pub body_type: BodyType,
+    // sent instead of `body` when the body type is multipart
+    pub parts: Option<Vec<MultipartPart>>,
+}
+
+/// How an endpoint task's body is sent
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub enum BodyType {
+    /// `body` is sent as JSON
+    Json,
+    /// `parts` are sent as multipart/form-data, e.g. to upload a config file or firmware image
+    Multipart,
+}
+
+impl Default for BodyType {
+    fn default() -> Self {
+        BodyType::Json
+    }
+}
+
+/// One part of a multipart/form-data body.
+///
+/// The value is rendered like the rest of the task, so it can be inline text with variables or reference an earlier
+/// task's output, e.g. `{{OUTPUT:Download firmware.response.content}}`. Task outputs are JSON, so binary data has to
+/// reach the part as a base64 string, which is decoded before sending when the part's encoding is `base64`.
+///
+/// Parts are held in memory while the request is sent. Together they're limited to `MULTIPART_MAX_BYTES` (50 MB by
+/// default) once decoded, and outputs carrying large files count towards the run's output size like any other.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct MultipartPart {
+    pub name: String,
+    pub value: String,
+    #[serde(default)]
+    pub encoding: PartEncoding,
+    // sends the part as a file
+    pub file_name: Option<String>,
+    pub content_type: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub enum PartEncoding {
+    Text,
+    Base64,
+}
+
+impl Default for PartEncoding {
+    fn default() -> Self {
+        PartEncoding::Text
+    }
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
@@ -107,6 +542,32 @@ impl Header {
 #[serde(rename_all = "camelCase")]
 pub struct LoopFields {
     pub tasks: Vec<TaskConfig>,
+    // number of assets processed per batch, all assets are processed as a single batch if omitted
+    pub batch_size: Option<usize>,
+    // fail the loop on an asset or device that can't be read, or when the tag has nothing for one of the loop's
+    // schema items, rather than carrying on without them
+    #[serde(default)]
+    pub strict_assets: bool,
+    // most assets the loop will iterate over, unlimited if omitted. See `on_overflow` for loops over more
+    pub max_iterations: Option<usize>,
+    #[serde(default)]
+    pub on_overflow: LoopOverflow,
+}
+
+/// What a loop does when its tag has more assets than its `maxIterations`
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub enum LoopOverflow {
+    /// iterate over the first `maxIterations` assets and log a warning
+    Cap,
+    /// fail the loop without running any iterations
+    Fail,
+}
+
+impl Default for LoopOverflow {
+    fn default() -> Self {
+        LoopOverflow::Cap
+    }
 }
 
 /**
@@ -118,29 +579,65 @@ pub struct ConditionalFields {
     pub expression: Vec<ConditionGroup>,
 }
 
+/// Conditions evaluated as if parenthesised, followed by any groups nested within it, so expressions can nest to any
+/// depth, e.g. `a AND (b OR c)` is a group with condition `a` and a nested group with conditions `b` and `c`. Groups
+/// without nested groups are the flat form every worker used before nesting was supported.
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct ConditionGroup {
+    // joins the group to the group before it, or for the first nested group to its parent's conditions
     #[serde(with = "serde_with::rust::string_empty_as_none")]
     pub op: Option<Operator>,
     pub conditions: Vec<Condition>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub groups: Vec<ConditionGroup>,
 }
 
-impl ConditionGroup {
-    pub fn eval(&self) -> Result<bool> {
-        let mut result = true;
-        let mut operator: Option<Operator> = None;
-        for condition in self.conditions.iter() {
-            result = match operator {
-                Some(Operator::And) => result && condition.eval()?,
-                Some(Operator::Or) => result || condition.eval()?,
-                None => condition.eval()?,
-            };
+// an operand within a group, see `ConditionGroup::eval`
+enum GroupOperand<'a> {
+    Condition(&'a Condition),
+    Group(&'a ConditionGroup),
+}
 
-            // keeping track of the previous operator, as operators are tied to the condition that precedes them in the JSON representation.
-            // Each condition actually needs to use the previous condition's operator to apply its result to the overall result.
-            operator = condition.op.clone();
+/// Evaluates operands joined by `AND`/`OR` the way boolean expressions are normally read: `AND` binds tighter than
+/// `OR`, so `a OR b AND c` is `a OR (b AND c)`, whatever order the operands appear in. The same rules apply to
+/// conditions within a group and to groups within a conditional, and groups behave as if parenthesised.
+///
+/// Each operand is paired with the operator joining it to the operand before it. The first operand's operator is
+/// ignored, and a missing operator anywhere else is treated as `AND`. Operands are evaluated left to right and
+/// skipped once they can no longer change the result, so an operand that would fail to evaluate only fails the
+/// expression if it's reached. An empty expression is true.
+pub fn eval_with_precedence<T>(
+    operands: impl IntoIterator<Item = (Option<Operator>, T)>,
+    eval: impl Fn(T) -> Result<bool>,
+) -> Result<bool> {
+    // the expression is evaluated as ORs of AND terms, `term` being the value of the current AND term
+    let mut term = true;
+    for (index, (operator, operand)) in operands.into_iter().enumerate() {
+        if index > 0 && operator == Some(Operator::Or) {
+            if term {
+                return Ok(true);
+            }
+            term = true;
         }
-        Ok(result)
+        if term {
+            term = eval(operand)?;
+        }
+    }
+    Ok(term)
+}
+
+impl ConditionGroup {
+    pub fn eval(&self) -> Result<bool> {
+        // operators are tied to the condition that precedes them in the JSON representation,
+        // so each condition is joined to the one before it by that condition's operator
+        let operators = std::iter::once(None).chain(self.conditions.iter().map(|condition| condition.op.clone()));
+        let conditions = operators.zip(self.conditions.iter().map(GroupOperand::Condition));
+        // nested groups carry the operator joining them to what comes before, like the groups of a conditional
+        let groups = self.groups.iter().map(|group| (group.op.clone(), GroupOperand::Group(group)));
+        eval_with_precedence(conditions.chain(groups), |operand| match operand {
+            GroupOperand::Condition(condition) => condition.eval(),
+            GroupOperand::Group(group) => group.eval(),
+        })
     }
 }
 
@@ -166,19 +663,19 @@ impl Condition {
                 Ok(parsed_var1 != parsed_var2)
             },
             Comparitor::GreaterThan => {
-                let (parsed_var1, parsed_var2) = self.parse_to_comparable(&self.var1, &self.var2)?;
+                let (parsed_var1, parsed_var2) = self.parse_to_ordered(&self.var1, &self.var2)?;
                 Ok(parsed_var1 > parsed_var2)
             },
             Comparitor::GreaterThanOrEqual => {
-                let (parsed_var1, parsed_var2) = self.parse_to_comparable(&self.var1, &self.var2)?;
+                let (parsed_var1, parsed_var2) = self.parse_to_ordered(&self.var1, &self.var2)?;
                 Ok(parsed_var1 >= parsed_var2)
             },
             Comparitor::LessThan => {
-                let (parsed_var1, parsed_var2) = self.parse_to_comparable(&self.var1, &self.var2)?;
+                let (parsed_var1, parsed_var2) = self.parse_to_ordered(&self.var1, &self.var2)?;
                 Ok(parsed_var1 < parsed_var2)
             },
             Comparitor::LessThanOrEqual => {
-                let (parsed_var1, parsed_var2) = self.parse_to_comparable(&self.var1, &self.var2)?;
+                let (parsed_var1, parsed_var2) = self.parse_to_ordered(&self.var1, &self.var2)?;
                 Ok(parsed_var1 <= parsed_var2)
             },
             // String comparitors
@@ -189,15 +686,43 @@ impl Condition {
             Comparitor::NotBeginsWith => Ok(!self.var1.starts_with(&self.var2)),
             Comparitor::EndsWith => Ok(self.var1.ends_with(&self.var2)),
             Comparitor::NotEndsWith => Ok(!self.var1.ends_with(&self.var2)),
+            // Case-insensitive comparitors
+            Comparitor::IEqual => self.eval_lowercase(Comparitor::Equal),
+            Comparitor::INotEqual => self.eval_lowercase(Comparitor::NotEqual),
+            Comparitor::IContains => self.eval_lowercase(Comparitor::Contains),
+            Comparitor::INotContains => self.eval_lowercase(Comparitor::NotContains),
+            Comparitor::IBeginsWith => self.eval_lowercase(Comparitor::BeginsWith),
+            Comparitor::INotBeginsWith => self.eval_lowercase(Comparitor::NotBeginsWith),
+            Comparitor::IEndsWith => self.eval_lowercase(Comparitor::EndsWith),
+            Comparitor::INotEndsWith => self.eval_lowercase(Comparitor::NotEndsWith),
         };
     }
 
+    // evaluates the condition with both operands lowercased, using the case-sensitive equivalent comparitor
+    fn eval_lowercase(&self, comparitor: Comparitor) -> Result<bool> {
+        Condition {
+            op: None,
+            comparitor,
+            var1: self.var1.to_lowercase(),
+            var2: self.var2.to_lowercase(),
+        }
+        .eval()
+    }
+
     fn parse_var(&self, var: &str) -> Var {
-        if let Ok(date) = var.parse::<DateTime<Utc>>() {
+        // JSON arrays and objects are compared structurally, so formatting and key order don't matter
+        let trimmed = var.trim_start();
+        if trimmed.starts_with('[') || trimmed.starts_with('{') {
+            if let Ok(json) = serde_json::from_str::<Value>(var) {
+                return Var::Json(json);
+            }
+        }
+
+        if let Some(date) = parse_date(var) {
             Var::Date(date)
-        } else if let Ok(float) = var.parse::<f64>() {
-            return Var::Number(float);
-        } else if let Ok(bool) = var.parse::<bool>() {
+        } else if let Some(number) = Number::parse(var) {
+            return Var::Number(number);
+        } else if let Some(bool) = parse_bool(var) {
             return Var::Boolean(bool);
         } else {
             return Var::String(var.to_string());
@@ -212,6 +737,14 @@ impl Condition {
         }
         return Ok((var1, var2));
     }
+
+    fn parse_to_ordered(&self, var1: &str, var2: &str) -> Result<(Var, Var)> {
+        let (var1, var2) = self.parse_to_comparable(var1, var2)?;
+        if let Var::Json(_) = var1 {
+            bail!("JSON arrays and objects can only be compared with == and !=")
+        }
+        return Ok((var1, var2));
+    }
 }
 
 #[derive(Serialize, Deserialize, Debug, PartialEq, Clone)]
@@ -269,9 +802,49 @@ pub enum Comparitor {
     EndsWith,
     #[serde(rename = "!ends_with")]
     NotEndsWith,
+    #[serde(rename = "iequals")]
+    IEqual,
+    #[serde(rename = "!iequals")]
+    INotEqual,
+    #[serde(rename = "icontains")]
+    IContains,
+    #[serde(rename = "!icontains")]
+    INotContains,
+    #[serde(rename = "ibegins_with")]
+    IBeginsWith,
+    #[serde(rename = "!ibegins_with")]
+    INotBeginsWith,
+    #[serde(rename = "iends_with")]
+    IEndsWith,
+    #[serde(rename = "!iends_with")]
+    INotEndsWith,
 }
 
 impl Comparitor {
+    /// Every comparitor, in the order they're offered to the worker builder
+    pub const ALL: [Comparitor; 20] = [
+        Comparitor::Equal,
+        Comparitor::NotEqual,
+        Comparitor::GreaterThan,
+        Comparitor::GreaterThanOrEqual,
+        Comparitor::LessThan,
+        Comparitor::LessThanOrEqual,
+        Comparitor::Contains,
+        Comparitor::NotContains,
+        Comparitor::BeginsWith,
+        Comparitor::NotBeginsWith,
+        Comparitor::EndsWith,
+        Comparitor::NotEndsWith,
+        Comparitor::IEqual,
+        Comparitor::INotEqual,
+        Comparitor::IContains,
+        Comparitor::INotContains,
+        Comparitor::IBeginsWith,
+        Comparitor::INotBeginsWith,
+        Comparitor::IEndsWith,
+        Comparitor::INotEndsWith,
+    ];
+
     pub fn to_string(&self) -> String {
         match self {
             Comparitor::Equal => "==",
@@ -286,20 +859,204 @@ impl Comparitor {
             Comparitor::NotBeginsWith => "!begins_with",
             Comparitor::EndsWith => "ends_with",
             Comparitor::NotEndsWith => "!ends_with",
+            Comparitor::IEqual => "iequals",
+            Comparitor::INotEqual => "!iequals",
+            Comparitor::IContains => "icontains",
+            Comparitor::INotContains => "!icontains",
+            Comparitor::IBeginsWith => "ibegins_with",
+            Comparitor::INotBeginsWith => "!ibegins_with",
+            Comparitor::IEndsWith => "iends_with",
+            Comparitor::INotEndsWith => "!iends_with",
         }
         .to_string()
     }
 }
 
-#[derive(Serialize, Deserialize, Debug, PartialEq, PartialOrd)]
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
 pub enum Var {
     String(String),
-    Number(f64),
+    Number(Number),
     Date(DateTime<Utc>),
     Boolean(bool),
+    Json(Value),
+}
+
+/// Integers that fit in an `i64` or `u64` are kept exact, so large IDs that only differ in their low-order digits
+/// aren't equal the way they would be as `f64`s. Integers are compared with floats as `f64`s
+#[derive(Serialize, Deserialize, Debug, Clone, Copy)]
+pub enum Number {
+    Integer(i128),
+    Float(f64),
+}
+
+impl Number {
+    fn parse(var: &str) -> Option<Number> {
+        if let Ok(int) = var.parse::<i64>() {
+            return Some(Number::Integer(int as i128));
+        }
+        if let Ok(uint) = var.parse::<u64>() {
+            return Some(Number::Integer(uint as i128));
+        }
+        var.parse::<f64>().ok().map(Number::Float)
+    }
+
+    fn as_f64(&self) -> f64 {
+        match self {
+            Number::Integer(int) => *int as f64,
+            Number::Float(float) => *float,
+        }
+    }
+}
+
+impl PartialEq for Number {
+    fn eq(&self, other: &Self) -> bool {
+        self.partial_cmp(other) == Some(Ordering::Equal)
+    }
+}
+
+impl PartialOrd for Number {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        match (self, other) {
+            (Number::Integer(int1), Number::Integer(int2)) => int1.partial_cmp(int2),
+            _ => self.as_f64().partial_cmp(&other.as_f64()),
+        }
+    }
+}
+
+// booleans rendered from outputs are `true`/`false`, other systems may capitalise them (e.g. Python's `True`)
+fn parse_bool(var: &str) -> Option<bool> {
+    match var.trim() {
+        var if var.eq_ignore_ascii_case("true") => Some(true),
+        var if var.eq_ignore_ascii_case("false") => Some(false),
+        _ => None,
+    }
+}
+
+// dates with an offset are converted to UTC. Dates and times without one are taken to be in UTC already, and a date
+// without a time is midnight UTC that day
+fn parse_date(var: &str) -> Option<DateTime<Utc>> {
+    if let Ok(date) = var.parse::<DateTime<Utc>>() {
+        return Some(date);
+    }
+    let with_offset = DateTime::parse_from_rfc3339(var)
+        .or_else(|_| DateTime::parse_from_str(var, "%Y-%m-%dT%H:%M:%S%.f%z"))
+        .or_else(|_| DateTime::parse_from_rfc2822(var));
+    if let Ok(date) = with_offset {
+        return Some(date.with_timezone(&Utc));
+    }
+    let naive = NaiveDateTime::parse_from_str(var, "%Y-%m-%dT%H:%M:%S%.f")
+        .or_else(|_| NaiveDateTime::parse_from_str(var, "%Y-%m-%d %H:%M:%S%.f"))
+        .ok()
+        .or_else(|| {
+            NaiveDate::parse_from_str(var, "%Y-%m-%d")
+                .ok()
+                .and_then(|date| date.and_hms_opt(0, 0, 0))
+        });
+    naive.map(|date| Utc.from_utc_datetime(&date))
+}
+
+// only variables of the same type are ordered. JSON values have no ordering, they're only ever equal or not
+impl PartialOrd for Var {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        match (self, other) {
+            (Var::String(var1), Var::String(var2)) => var1.partial_cmp(var2),
+            (Var::Number(var1), Var::Number(var2)) => var1.partial_cmp(var2),
+            (Var::Date(var1), Var::Date(var2)) => var1.partial_cmp(var2),
+            (Var::Boolean(var1), Var::Boolean(var2)) => var1.partial_cmp(var2),
+            (Var::Json(var1), Var::Json(var2)) if var1 == var2 => Some(Ordering::Equal),
+            _ => None,
+        }
+    }
 }
 
 
+/**
+ * Custom outputs
+ */
+/// Expected shape of the custom output a waiting task is resumed with, keyed by field name, e.g.
+/// `{"decision": {"type": "string", "required": true}, "comment": {"type": "string"}}`. The output has to be an
+/// object, fields the schema doesn't declare are accepted as they are
+pub type OutputSchema = HashMap<String, OutputField>;
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct OutputField {
+    #[serde(rename = "type")]
+    pub field_type: OutputFieldType,
+    #[serde(default)]
+    pub required: bool,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub enum OutputFieldType {
+    String,
+    Number,
+    Boolean,
+    Object,
+    Array,
+}
+
+impl OutputFieldType {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            OutputFieldType::String => "string",
+            OutputFieldType::Number => "number",
+            OutputFieldType::Boolean => "boolean",
+            OutputFieldType::Object => "object",
+            OutputFieldType::Array => "array",
+        }
+    }
+
+    pub fn matches(&self, value: &Value) -> bool {
+        match self {
+            OutputFieldType::String => value.is_string(),
+            OutputFieldType::Number => value.is_number(),
+            OutputFieldType::Boolean => value.is_boolean(),
+            OutputFieldType::Object => value.is_object(),
+            OutputFieldType::Array => value.is_array(),
+        }
+    }
+}
+
+/// Checks a custom output against the schema, naming the first field that doesn't fit. Null fields count as missing
+pub fn validate_output(schema: &OutputSchema, output: &Value) -> Result<()> {
+    let fields = match output.as_object() {
+        Some(fields) => fields,
+        None => bail!("expected an object with fields {}", schema_fields(schema)),
+    };
+    let mut names = schema.keys().collect::<Vec<&String>>();
+    names.sort();
+    for name in names {
+        let field = &schema[name];
+        match fields.get(name) {
+            None | Some(Value::Null) if field.required => bail!("missing required field {}", name),
+            None | Some(Value::Null) => {}
+            Some(value) if !field.field_type.matches(value) => {
+                bail!("field {} should be a {}", name, field.field_type.as_str())
+            }
+            Some(_) => {}
+        }
+    }
+    Ok(())
+}
+
+fn schema_fields(schema: &OutputSchema) -> String {
+    let mut names = schema.keys().map(String::as_str).collect::<Vec<&str>>();
+    names.sort();
+    names.join(", ")
+}
+
+/**
+ * Switch tasks
+ */
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct SwitchFields {
+    // rendered, then matched against the keys of the task's `Next::cases`
+    pub value: String,
+}
+
 /**
  * Filter tasks
  */
@@ -308,8 +1065,13 @@ pub enum Var {
 pub struct FilterFields {
     pub condition: String,
     pub object_to_filter: String,
+    // not needed when filtering by `pointer`
+    #[serde(default)]
     pub search_key: String,
     pub search_value: String,
+    // JSON pointer (RFC 6901, e.g. /interfaces/0/interface) to the only value the condition is applied to, in place
+    // of searching the whole object for `search_key`
+    pub pointer: Option<String>,
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
@@ -318,6 +1080,27 @@ pub struct Next {
     pub true_branch: Option<String>,
     #[serde(rename = "false")]
     pub false_branch: Option<String>,
+    // switch tasks branch on their value instead, mapping values to react IDs
+    #[serde(default, skip_serializing_if = "HashMap::is_empty")]
+    pub cases: HashMap<String, String>,
+    // followed by switch tasks whose value has no case
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub default: Option<String>,
+    // fans out, running these branches concurrently with the true branch. Each branch runs until it reaches `merge`
+    // or ends, then the worker carries on from `merge` once they've all succeeded. A conditional, filter or switch that
+    // takes another branch follows only that branch
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub parallel: Vec<String>,
+    // where the branches of a fan-out rejoin. Without one the worker finishes with its branches
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub merge: Option<String>,
+}
+
+impl Next {
+    // whether there's no branch at all to follow, i.e. the task is the last one
+    pub fn is_empty(&self) -> bool {
+        self.true_branch.is_none() && self.false_branch.is_none() && self.cases.is_empty() && self.default.is_none()
+    }
 }
 
 // #[derive(Serialize, Deserialize, Debug, Clone)]
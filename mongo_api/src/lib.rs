@@ -1,13 +1,15 @@
-use bson::Document;
+use bson::{Bson, Document};
 use futures::stream::TryStreamExt;
 use mongodb::{
     bson::{doc, extjson::de::Error, oid::ObjectId},
-    options::UpdateModifications,
+    error::{ErrorKind, WriteFailure},
+    options::{IndexOptions, UpdateModifications, UpdateOptions},
     results::{DeleteResult, InsertOneResult, UpdateResult},
-    Client, Collection, Database,
+    Client, Collection, Database, IndexModel,
 };
 use serde::{de::DeserializeOwned, Serialize};
 use std::fmt::Debug;
+use tracing::{debug, error};
 pub trait MongoDbModel: Sync + Send + Unpin {
     fn model_name() -> String;
 }
@@ -30,24 +32,64 @@ impl MongoDbClient {
         MongoDbClient { client, db }
     }
 
+    /// Creates the indexes the API's queries rely on. Mongo treats creating an index that already exists
+    /// as a no-op, so this is safe to call on every startup.
+    pub async fn ensure_indexes(&self) -> mongodb::error::Result<()> {
+        let indexes = [
+            // single asset/device/integration lookups by exact SK, and listings by an anchored SK prefix regex
+            // (e.g. ^asset#meraki#<integration id>#), which can only use the index when PK comes first
+            ("Asset", doc! {"PK": 1, "SK": 1}, "PK_SK"),
+            ("Device", doc! {"PK": 1, "SK": 1}, "PK_SK"),
+            ("Integration", doc! {"PK": 1, "SK": 1}, "PK_SK"),
+            ("WorkerDefinition", doc! {"PK": 1, "SK": 1}, "PK_SK"),
+            // execution records are looked up by execution ID alone when retrying
+            ("ExecutionRecord", doc! {"SK": 1}, "SK"),
+            // earlier runs of the same worker config, by its content hash
+            ("ExecutionRecord", doc! {"PK": 1, "contentHash": 1}, "PK_contentHash"),
+            // an execution's secrets, read when its runs are resumed or retried
+            ("ExecutionSecrets", doc! {"SK": 1}, "SK"),
+            // assets-by-tags, both per tag lookups and $all matches
+            ("Asset", doc! {"PK": 1, "attributes.assetTags": 1}, "PK_assetTags"),
+            ("Device", doc! {"PK": 1, "attributes.deviceTags": 1}, "PK_deviceTags"),
+            // get-all-tags, distinct tags for a tenant filtered by an anchored SK prefix regex (^tag#<prefix>)
+            ("AssetTag", doc! {"SK1": 1, "SK": 1}, "SK1_SK"),
+        ];
+
+        // suspended runs are claimed with a conditional upsert, which relies on there being one document per SK
+        let unique_indexes = [("RunRecord", doc! {"SK": 1}, "SK")];
+
+        let indexes = indexes
+            .into_iter()
+            .map(|index| (index, false))
+            .chain(unique_indexes.into_iter().map(|index| (index, true)));
+        for ((collection, keys, name), unique) in indexes {
+            let index = IndexModel::builder()
+                .keys(keys)
+                .options(IndexOptions::builder().name(name.to_string()).unique(unique).build())
+                .build();
+            self.db
+                .collection::<Document>(collection)
+                .create_index(index, None)
+                .await?;
+        }
+        Ok(())
+    }
+
     fn get_collection<T: MongoDbModel>(&self) -> Collection<T> {
         let model_name = T::model_name().clone();
         let col = self.db.collection::<T>(&model_name);
         col
     }
 
-    pub async fn insert_one<T>(&self, data: &T) -> Result<InsertOneResult, Error>
+    pub async fn insert_one<T>(&self, data: &T) -> mongodb::error::Result<InsertOneResult>
     where
         T: MongoDbModel + Serialize,
     {
         let col = self.get_collection::<T>();
-        match col.insert_one(data, None).await {
-            Ok(ret) => Ok(ret),
-            Err(e) => {
-                println!("error occured while inserting data: {}", e);
-                panic!("error occured while inserting data")
-            }
-        }
+        col.insert_one(data, None).await.map_err(|e| {
+            error!(%e, "error occured while inserting data");
+            e
+        })
     }
 
     pub async fn find_by_id<T>(&self, id: &str) -> Result<T, Error>
@@ -86,6 +128,22 @@ impl MongoDbClient {
         Ok(ret)
     }
 
+    // replaces the whole document, unlike `update_item` which leaves fields missing from `new_item` in place
+    pub async fn replace_item<T>(&self, id: &str, new_item: T) -> Result<UpdateResult, Error>
+    where
+        T: MongoDbModel + Serialize + DeserializeOwned,
+    {
+        let col = self.get_collection::<T>();
+        let obj_id = ObjectId::parse_str(id).expect("there is no id for replace");
+        let filter = doc! {"_id": obj_id};
+        let ret = col
+            .replace_one(filter, new_item, None)
+            .await
+            .ok()
+            .expect("Error replacing document");
+        Ok(ret)
+    }
+
     pub async fn delete_item<T>(&self, id: &str) -> Result<DeleteResult, Error>
     where
         T: MongoDbModel + DeserializeOwned,
@@ -109,7 +167,7 @@ impl MongoDbClient {
         let mut obj_ids: Vec<ObjectId> = Vec::new();
         items.iter().for_each(|v| match ObjectId::parse_str(v) {
             Ok(val) => obj_ids.push(val),
-            Err(e) => println!("one of id is not correct. :{}", e),
+            Err(e) => error!(id = %v, %e, "one of id is not correct"),
         });
 
         let ids = bson::to_bson(&obj_ids).expect("Error converting items for massive delete");
@@ -128,8 +186,7 @@ impl MongoDbClient {
         T: MongoDbModel + Serialize + DeserializeOwned,
     {
         let col = self.get_collection::<T>();
-        println!("{}", T::model_name());
-        dbg!(&filter);
+        debug!(model = %T::model_name(), ?filter, "filtering items");
         let mut cursors = col
             .find(filter, None)
             .await
@@ -168,6 +225,52 @@ impl MongoDbClient {
         Ok(None)
     }
 
+    pub async fn distinct<T>(&self, field_name: &str, filter: Option<Document>) -> Result<Vec<Bson>, Error>
+    where
+        T: MongoDbModel,
+    {
+        let col = self.get_collection::<T>();
+        debug!(field_name, ?filter, "distinct values");
+        let ret = col
+            .distinct(field_name, filter, None)
+            .await
+            .unwrap_or_else(|e| panic!("Error getting distinct values: {}", e));
+        Ok(ret)
+    }
+
+    /// Runs an aggregation `pipeline` over the collection, returning the resulting documents
+    pub async fn aggregate<T>(&self, pipeline: Vec<Document>) -> mongodb::error::Result<Vec<Document>>
+    where
+        T: MongoDbModel,
+    {
+        let col = self.get_collection::<T>();
+        debug!(?pipeline, "aggregating");
+        col.aggregate(pipeline, None).await?.try_collect().await
+    }
+
+    /// Applies `update` to the document matching `filter`, inserting one if there's none
+    pub async fn upsert_item<T>(&self, filter: Document, update: Document) -> mongodb::error::Result<UpdateResult>
+    where
+        T: MongoDbModel,
+    {
+        let col = self.get_collection::<T>();
+        let options = UpdateOptions::builder().upsert(true).build();
+        col.update_one(filter, update, options).await
+    }
+
+    /// Like `upsert_item`, for a `filter` on a uniquely indexed field plus a condition. False when a document with
+    /// the unique field exists but doesn't meet the condition, as the upsert then collides with it
+    pub async fn upsert_item_if<T>(&self, filter: Document, update: Document) -> mongodb::error::Result<bool>
+    where
+        T: MongoDbModel,
+    {
+        match self.upsert_item::<T>(filter, update).await {
+            Ok(_) => Ok(true),
+            Err(err) if is_duplicate_key(&err) => Ok(false),
+            Err(err) => Err(err),
+        }
+    }
+
     pub async fn update_items<T>(
         &self,
         query: Document,
@@ -188,3 +291,13 @@ impl MongoDbClient {
         Ok(ret)
     }
 }
+
+const DUPLICATE_KEY: i32 = 11000;
+
+fn is_duplicate_key(err: &mongodb::error::Error) -> bool {
+    match err.kind.as_ref() {
+        ErrorKind::Write(WriteFailure::WriteError(err)) => err.code == DUPLICATE_KEY,
+        ErrorKind::Command(err) => err.code == DUPLICATE_KEY,
+        _ => false,
+    }
+}
@@ -0,0 +1,34 @@
+// What sharing the worker runtime saves over building one per trigger, as `execute` used to. Timings depend on the
+// machine, so this only reports them. Run with `cargo run -p xpertly_worker --release --example runtime_reuse`
+use std::time::Instant;
+
+const TRIGGERS: u32 = 200;
+
+// a trigger's share of runtime work, spawning a handful of tasks and waiting for them
+async fn trigger() {
+    let tasks = (0..10).map(|_| tokio::spawn(tokio::task::yield_now())).collect::<Vec<_>>();
+    for task in tasks {
+        task.await.unwrap();
+    }
+}
+
+fn main() {
+    let started = Instant::now();
+    for _ in 0..TRIGGERS {
+        tokio::runtime::Runtime::new().unwrap().block_on(trigger());
+    }
+    let per_trigger = started.elapsed();
+
+    // built before timing, as it is by the first trigger
+    xpertly_worker::runtime();
+    let started = Instant::now();
+    for _ in 0..TRIGGERS {
+        xpertly_worker::runtime().block_on(trigger());
+    }
+    let shared = started.elapsed();
+
+    println!(
+        "{} triggers: {:?} building a runtime for each, {:?} on the shared runtime",
+        TRIGGERS, per_trigger, shared
+    );
+}
@@ -0,0 +1,155 @@
+use crate::task::endpoint::egress::{self, EgressResolver};
+use crate::WorkerInvocation;
+use async_trait::async_trait;
+use core::fmt;
+use once_cell::sync::Lazy;
+use reqwest::redirect::Policy;
+use reqwest::{Client, ClientBuilder, Request, RequestBuilder, Response};
+use std::sync::Arc;
+use std::time::Duration;
+
+const DEFAULT_CONNECT_TIMEOUT_MS: u64 = 10_000;
+const DEFAULT_REQUEST_TIMEOUT_MS: u64 = 120_000;
+
+fn timeout_from_env(var: &str, default_ms: u64) -> Duration {
+    let timeout = std::env::var(var)
+        .ok()
+        .and_then(|timeout| timeout.parse::<u64>().ok())
+        .filter(|timeout| *timeout > 0)
+        .unwrap_or(default_ms);
+    Duration::from_millis(timeout)
+}
+
+// how long to wait for a connection to be established, set with the HTTP_CONNECT_TIMEOUT_MS env var
+static CONNECT_TIMEOUT: Lazy<Duration> =
+    Lazy::new(|| timeout_from_env("HTTP_CONNECT_TIMEOUT_MS", DEFAULT_CONNECT_TIMEOUT_MS));
+// how long a whole request may take, response body included, set with the HTTP_REQUEST_TIMEOUT_MS env var
+static REQUEST_TIMEOUT: Lazy<Duration> =
+    Lazy::new(|| timeout_from_env("HTTP_REQUEST_TIMEOUT_MS", DEFAULT_REQUEST_TIMEOUT_MS));
+
+// redirects followed per request, set with the HTTP_MAX_REDIRECTS env var. Vendor APIs tend to redirect to a login
+// page when credentials are rejected, so by default redirects aren't followed and the 3xx is reported as is
+static MAX_REDIRECTS: Lazy<usize> = Lazy::new(|| {
+    std::env::var("HTTP_MAX_REDIRECTS")
+        .ok()
+        .and_then(|max| max.parse::<usize>().ok())
+        .unwrap_or(0)
+});
+
+// each redirect is checked against the egress rules like the first request, see `egress::check`
+fn redirect_policy(max_redirects: usize) -> Policy {
+    if max_redirects == 0 {
+        return Policy::none();
+    }
+    Policy::custom(move |attempt| {
+        if attempt.previous().len() >= max_redirects {
+            attempt.error(format!("too many redirects, at most {} are followed", max_redirects))
+        } else if let Err(err) = egress::check(attempt.url()) {
+            attempt.error(err.to_string())
+        } else {
+            attempt.follow()
+        }
+    })
+}
+
+const DEFAULT_USER_AGENT: &str = concat!("xpertly-worker/", env!("CARGO_PKG_VERSION"));
+
+// User-Agent sent with every request, set with the HTTP_USER_AGENT env var. Integrations and workers can override it
+// for endpoint requests, see `Integration::user_agent`
+static USER_AGENT: Lazy<String> = Lazy::new(|| {
+    std::env::var("HTTP_USER_AGENT")
+        .ok()
+        .filter(|user_agent| !user_agent.trim().is_empty())
+        .unwrap_or_else(|| String::from(DEFAULT_USER_AGENT))
+});
+
+/// Builds a reqwest `Client` with the configured connect and request timeouts, redirect policy and the global
+/// User-Agent. Every client should be built with this rather than `Client::new()`, which never times out, so an
+/// unreachable host can't hang a task indefinitely.
+pub fn build_client() -> Client {
+    builder().build().expect("failed to build HTTP client")
+}
+
+/// Like `build_client`, for the requests endpoint tasks send to tenant supplied URLs. Hostnames are resolved through
+/// the egress rules, see `egress::EgressResolver`
+pub fn build_endpoint_client() -> Client {
+    builder()
+        .dns_resolver(Arc::new(EgressResolver))
+        .build()
+        .expect("failed to build HTTP client")
+}
+
+fn builder() -> ClientBuilder {
+    Client::builder()
+        .user_agent(USER_AGENT.as_str())
+        .redirect(redirect_policy(*MAX_REDIRECTS))
+        .connect_timeout(*CONNECT_TIMEOUT)
+        .timeout(*REQUEST_TIMEOUT)
+}
+
+/// Sends the HTTP requests made during an invocation. Requests are still built with the invocation's reqwest
+/// `Client`, only sending them goes through this trait, so tests can swap in a client that doesn't touch the network.
+#[async_trait]
+pub trait HttpClient: fmt::Debug + Send + Sync {
+    async fn execute(&self, request: Request) -> reqwest::Result<Response>;
+
+    /// Sends an endpoint task's request, connecting only to addresses the egress rules allow. Clients that don't
+    /// connect anywhere can leave it to `execute`
+    async fn execute_endpoint(&self, request: Request) -> reqwest::Result<Response> {
+        self.execute(request).await
+    }
+}
+
+/// What invocations send requests with unless they're given another client. Endpoint task requests go through a
+/// client of their own, built with `build_endpoint_client`
+#[derive(Debug, Clone)]
+pub struct DefaultHttpClient {
+    client: Client,
+    endpoint_client: Client,
+}
+
+impl DefaultHttpClient {
+    pub fn new() -> Self {
+        DefaultHttpClient {
+            client: build_client(),
+            endpoint_client: build_endpoint_client(),
+        }
+    }
+}
+
+impl Default for DefaultHttpClient {
+    fn default() -> Self {
+        DefaultHttpClient::new()
+    }
+}
+
+#[async_trait]
+impl HttpClient for DefaultHttpClient {
+    async fn execute(&self, request: Request) -> reqwest::Result<Response> {
+        self.client.execute(request).await
+    }
+
+    async fn execute_endpoint(&self, request: Request) -> reqwest::Result<Response> {
+        self.endpoint_client.execute(request).await
+    }
+}
+
+#[async_trait]
+impl HttpClient for Client {
+    async fn execute(&self, request: Request) -> reqwest::Result<Response> {
+        Client::execute(self, request).await
+    }
+}
+
+/// Sends a request through the invocation's [`HttpClient`], used in place of `RequestBuilder::send`.
+#[async_trait]
+pub trait SendVia {
+    async fn send_via(self, context: &WorkerInvocation) -> reqwest::Result<Response>;
+}
+
+#[async_trait]
+impl SendVia for RequestBuilder {
+    async fn send_via(self, context: &WorkerInvocation) -> reqwest::Result<Response> {
+        context.http_client.execute(self.build()?).await
+    }
+}
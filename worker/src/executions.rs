@@ -0,0 +1,169 @@
+// Executions started or resumed by this process, for status queries. An execution is kept while any of its tags'
+// runs is still going and for a retention period once they've all finished, after which it's evicted. One left
+// waiting for a callback is evicted once it has waited longer than a callback could arrive. Evicted execution IDs
+// are remembered for a while longer, so a query for one can say it expired rather than that it never existed.
+use crate::{InvocationState, TagOutcome, TagOutcomes};
+use chrono::{DateTime, Utc};
+use once_cell::sync::Lazy;
+use parking_lot::Mutex;
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet, VecDeque};
+use uuid::Uuid;
+
+const DEFAULT_RETENTION_SECS: i64 = 3600;
+// wait tokens expire after a day by default, a callback can't resume the run after that
+const DEFAULT_WAITING_TTL_SECS: i64 = 24 * 3600;
+// how many evicted execution IDs are remembered, the oldest are forgotten first
+const MAX_EXPIRED: usize = 10_000;
+
+// how long a finished execution is kept, set with the EXECUTION_RETENTION_SECS env var
+static RETENTION: Lazy<chrono::Duration> = Lazy::new(|| {
+    let secs = std::env::var("EXECUTION_RETENTION_SECS")
+        .ok()
+        .and_then(|secs| secs.parse::<i64>().ok())
+        .filter(|secs| *secs >= 0)
+        .unwrap_or(DEFAULT_RETENTION_SECS);
+    chrono::Duration::seconds(secs)
+});
+
+// how long an execution waiting for a callback is kept since it last changed, set with the EXECUTION_WAITING_TTL_SECS
+// env var
+static WAITING_TTL: Lazy<chrono::Duration> = Lazy::new(|| {
+    let secs = std::env::var("EXECUTION_WAITING_TTL_SECS")
+        .ok()
+        .and_then(|secs| secs.parse::<i64>().ok())
+        .filter(|secs| *secs > 0)
+        .unwrap_or(DEFAULT_WAITING_TTL_SECS);
+    chrono::Duration::seconds(secs)
+});
+
+static EXECUTIONS: Lazy<Mutex<Registry>> = Lazy::new(|| Mutex::new(Registry::default()));
+
+/// An execution's state and how each of its tags' runs is going, in the order they were triggered like `TagOutcomes`.
+/// Runs that haven't finished yet have no result
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct ExecutionStatus {
+    pub execution_id: Uuid,
+    pub tenant_id: Uuid,
+    /// `failed` once every run has finished and any of them failed, `complete` once they've all completed,
+    /// `waiting` while every unfinished run is waiting for a callback and `running` otherwise
+    pub state: InvocationState,
+    pub tags: TagOutcomes,
+    pub finished_at: Option<DateTime<Utc>>,
+    /// when a run last started or finished
+    pub updated_at: DateTime<Utc>,
+}
+
+#[derive(Debug, Clone)]
+pub enum Lookup {
+    Found(ExecutionStatus),
+    /// finished and evicted after the retention period
+    Expired,
+    Unknown,
+}
+
+#[derive(Debug, Default)]
+pub(crate) struct Registry {
+    executions: HashMap<Uuid, ExecutionStatus>,
+    expired: VecDeque<Uuid>,
+    expired_ids: HashSet<Uuid>,
+}
+
+impl Registry {
+    // records a tag's run as it starts or finishes, replacing anything recorded for the same run before
+    pub(crate) fn record(&mut self, execution_id: Uuid, tenant_id: Uuid, outcome: TagOutcome, now: DateTime<Utc>) {
+        let execution = self.executions.entry(execution_id).or_insert_with(|| ExecutionStatus {
+            execution_id,
+            tenant_id,
+            state: InvocationState::Running,
+            tags: Vec::new(),
+            finished_at: None,
+            updated_at: now,
+        });
+        execution.updated_at = now;
+        match execution.tags.iter_mut().find(|recorded| recorded.run_id == outcome.run_id) {
+            Some(recorded) => *recorded = outcome,
+            None => execution.tags.push(outcome),
+        }
+        execution.state = overall_state(&execution.tags);
+        execution.finished_at = match execution.state {
+            InvocationState::Complete | InvocationState::Failed => execution.finished_at.or(Some(now)),
+            _ => None,
+        };
+    }
+
+    pub(crate) fn evict(&mut self, now: DateTime<Utc>, retention: chrono::Duration, waiting_ttl: chrono::Duration) {
+        let evicted = self
+            .executions
+            .values()
+            .filter(|execution| match execution.finished_at {
+                Some(finished_at) => finished_at + retention <= now,
+                None => execution.state == InvocationState::Waiting && execution.updated_at + waiting_ttl <= now,
+            })
+            .map(|execution| execution.execution_id)
+            .collect::<Vec<Uuid>>();
+        for execution_id in evicted {
+            self.executions.remove(&execution_id);
+            if self.expired_ids.insert(execution_id) {
+                self.expired.push_back(execution_id);
+            }
+        }
+        while self.expired.len() > MAX_EXPIRED {
+            if let Some(forgotten) = self.expired.pop_front() {
+                self.expired_ids.remove(&forgotten);
+            }
+        }
+    }
+
+    pub(crate) fn lookup(&self, execution_id: Uuid) -> Lookup {
+        match self.executions.get(&execution_id) {
+            Some(execution) => Lookup::Found(execution.clone()),
+            None if self.expired_ids.contains(&execution_id) => Lookup::Expired,
+            None => Lookup::Unknown,
+        }
+    }
+
+    pub(crate) fn len(&self) -> usize {
+        self.executions.len()
+    }
+}
+
+fn overall_state(tags: &TagOutcomes) -> InvocationState {
+    let states = tags.iter().map(|outcome| outcome.state).collect::<Vec<InvocationState>>();
+    if states.iter().any(|state| matches!(state, InvocationState::Pending | InvocationState::Running)) {
+        InvocationState::Running
+    } else if states.contains(&InvocationState::Waiting) {
+        InvocationState::Waiting
+    } else if states.contains(&InvocationState::Failed) {
+        InvocationState::Failed
+    } else {
+        InvocationState::Complete
+    }
+}
+
+pub(crate) fn record(execution_id: Uuid, tenant_id: Uuid, outcome: TagOutcome) {
+    let now = Utc::now();
+    let mut executions = EXECUTIONS.lock();
+    executions.evict(now, *RETENTION, *WAITING_TTL);
+    executions.record(execution_id, tenant_id, outcome, now);
+}
+
+/// The execution's status, if it's still kept
+pub fn lookup(execution_id: Uuid) -> Lookup {
+    let mut executions = EXECUTIONS.lock();
+    executions.evict(Utc::now(), *RETENTION, *WAITING_TTL);
+    executions.lookup(execution_id)
+}
+
+/// Number of executions kept for status queries, running or finished within the retention period
+pub fn tracked_executions() -> usize {
+    let mut executions = EXECUTIONS.lock();
+    executions.evict(Utc::now(), *RETENTION, *WAITING_TTL);
+    executions.len()
+}
+
+/// How long finished executions are kept for status queries
+pub fn retention() -> chrono::Duration {
+    *RETENTION
+}
@@ -0,0 +1,168 @@
+// Where each suspended run is in its lifecycle, so a callback can only complete its run once. A resume or cancel
+// claims the run by moving it from waiting to running before anything is executed, a duplicate callback arriving
+// while the run is still going (or after it's finished) finds it already claimed. The run is recorded as waiting again
+// if it suspends at a later task, and with its final state once it finishes. Recorded in Mongo when there's a
+// database, so claims survive a restart and are shared by every API instance.
+use crate::InvocationState;
+use anyhow::Result;
+use async_trait::async_trait;
+use chrono::{DateTime, Utc};
+use core::fmt;
+use mongo_api::{MongoDbClient, MongoDbModel};
+use mongo_derive::MongoModel;
+use mongodb::bson::{doc, oid::ObjectId};
+use once_cell::sync::Lazy;
+use parking_lot::{Mutex, RwLock};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::sync::Arc;
+use tracing::warn;
+use uuid::Uuid;
+
+// how long a finished run is remembered by `MemoryRunStore`. Wait tokens expire after a day by default, after which
+// a callback is rejected anyway
+const FINISHED_RETENTION_SECS: i64 = 7 * 24 * 3600;
+
+static STORE: Lazy<RwLock<Arc<dyn RunStore>>> = Lazy::new(|| RwLock::new(Arc::new(MemoryRunStore::default())));
+
+/// Records the state of suspended runs, see `claim`.
+#[async_trait]
+pub trait RunStore: fmt::Debug + Send + Sync {
+    /// Records the run's state, replacing whatever was recorded for it
+    async fn record(&self, run_id: Uuid, state: InvocationState) -> Result<()>;
+    /// Atomically moves a waiting run to running, false if it isn't waiting. A run with no record is claimed as well,
+    /// it was suspended before runs were recorded
+    async fn claim(&self, run_id: Uuid) -> Result<bool>;
+}
+
+/// Keeps run states in memory, so claims are only seen by this process and are lost on restart.
+#[derive(Debug, Default)]
+pub struct MemoryRunStore {
+    runs: Mutex<HashMap<Uuid, (InvocationState, DateTime<Utc>)>>,
+}
+
+impl MemoryRunStore {
+    // forgets runs that finished over `FINISHED_RETENTION_SECS` ago
+    fn evict(runs: &mut HashMap<Uuid, (InvocationState, DateTime<Utc>)>, now: DateTime<Utc>) {
+        let retention = chrono::Duration::seconds(FINISHED_RETENTION_SECS);
+        runs.retain(|_, (state, updated_at)| {
+            !matches!(state, InvocationState::Complete | InvocationState::Failed) || now - *updated_at < retention
+        });
+    }
+}
+
+#[async_trait]
+impl RunStore for MemoryRunStore {
+    async fn record(&self, run_id: Uuid, state: InvocationState) -> Result<()> {
+        let now = Utc::now();
+        let mut runs = self.runs.lock();
+        MemoryRunStore::evict(&mut runs, now);
+        runs.insert(run_id, (state, now));
+        Ok(())
+    }
+
+    async fn claim(&self, run_id: Uuid) -> Result<bool> {
+        let now = Utc::now();
+        let mut runs = self.runs.lock();
+        MemoryRunStore::evict(&mut runs, now);
+        match runs.get(&run_id) {
+            Some((InvocationState::Waiting, _)) | None => {
+                runs.insert(run_id, (InvocationState::Running, now));
+                Ok(true)
+            }
+            Some(_) => Ok(false),
+        }
+    }
+}
+
+/// A suspended run's state, one document per run
+#[derive(Serialize, Deserialize, Debug, Clone, MongoModel)]
+#[serde(rename_all = "camelCase")]
+pub struct RunRecord {
+    #[serde(rename = "_id", skip_serializing_if = "Option::is_none")]
+    pub id: Option<ObjectId>,
+    #[serde(rename = "SK")]
+    pub sort_key: String,
+    pub state: InvocationState,
+    pub updated_at: DateTime<Utc>,
+}
+
+impl RunRecord {
+    pub fn sort_key(run_id: &Uuid) -> String {
+        format!("run#{}", run_id)
+    }
+}
+
+/// Keeps run states in the `RunRecord` collection. Claims rely on its unique SK index, see
+/// `MongoDbClient::ensure_indexes`
+#[derive(Clone)]
+pub struct MongoRunStore {
+    db: MongoDbClient,
+}
+
+impl MongoRunStore {
+    pub fn new(db: MongoDbClient) -> Self {
+        MongoRunStore { db }
+    }
+
+    fn update(state: InvocationState) -> Result<mongodb::bson::Document> {
+        Ok(doc! {"$set": {
+            "state": mongodb::bson::to_bson(&state)?,
+            "updatedAt": Utc::now().to_rfc3339(),
+        }})
+    }
+}
+
+impl fmt::Debug for MongoRunStore {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("MongoRunStore").finish()
+    }
+}
+
+#[async_trait]
+impl RunStore for MongoRunStore {
+    async fn record(&self, run_id: Uuid, state: InvocationState) -> Result<()> {
+        let filter = doc! {"SK": RunRecord::sort_key(&run_id)};
+        self.db.upsert_item::<RunRecord>(filter, MongoRunStore::update(state)?).await?;
+        Ok(())
+    }
+
+    async fn claim(&self, run_id: Uuid) -> Result<bool> {
+        // matches a waiting run, or inserts a running one if there's none. A run in any other state collides with
+        // the upsert
+        let filter = doc! {
+            "SK": RunRecord::sort_key(&run_id),
+            "state": mongodb::bson::to_bson(&InvocationState::Waiting)?,
+        };
+        let update = MongoRunStore::update(InvocationState::Running)?;
+        Ok(self.db.upsert_item_if::<RunRecord>(filter, update).await?)
+    }
+}
+
+/// Replaces the store runs are recorded in, runs are kept in memory until this is called
+pub fn set_store(store: Arc<dyn RunStore>) {
+    *STORE.write() = store;
+}
+
+fn store() -> Arc<dyn RunStore> {
+    STORE.read().clone()
+}
+
+/// Claims a waiting run for a resume or cancel, false if it's already been claimed or has finished. Callers that
+/// claim a run and then don't go ahead with it should `release` it
+pub async fn claim(run_id: Uuid) -> Result<bool> {
+    store().claim(run_id).await
+}
+
+/// Puts a claimed run back to waiting, so its callback can be retried
+pub async fn release(run_id: Uuid) {
+    record(run_id, InvocationState::Waiting).await;
+}
+
+// a failure to record is logged rather than failing the run, the suspended payload's state still guards against
+// resuming a finished run
+pub(crate) async fn record(run_id: Uuid, state: InvocationState) {
+    if let Err(err) = store().record(run_id, state).await {
+        warn!(%run_id, %err, %state, "failed to record run state");
+    }
+}
@@ -0,0 +1,294 @@
+// Secrets in a worker's global and custom values, kept under keys prefixed `secret:` and referenced like any other
+// value, e.g. {{GLOBAL:secret:apiToken}} or {{CUSTOM:secret:password}}. They're substituted into tasks as usual, but
+// redacted wherever the worker is serialized (the suspension payload) and masked in logs and saved outputs, so they
+// only leave the worker in the requests that use them.
+//
+// A suspended run's payload and the execution's record have their secrets redacted. The secrets are saved apart from
+// them as the execution starts (see `save`) and restored whenever a suspended run is rehydrated, see
+// `WorkerInvocation::restore_secrets`. A run whose secrets can't be restored fails rather than running with
+// `[REDACTED]` in their place. Saved in Mongo when there's a database, otherwise they're only kept by this process.
+use anyhow::{bail, Result};
+use async_trait::async_trait;
+use chrono::{DateTime, Utc};
+use core::fmt;
+use mongo_api::{MongoDbClient, MongoDbModel};
+use mongo_derive::MongoModel;
+use mongodb::bson::{doc, oid::ObjectId};
+use once_cell::sync::Lazy;
+use parking_lot::{Mutex, RwLock};
+use serde::{Deserialize, Serialize, Serializer};
+use serde_json::Value;
+use std::collections::HashMap;
+use std::sync::Arc;
+use uuid::Uuid;
+use xpertly_common::WorkerConfig;
+
+pub const SECRET_PREFIX: &str = "secret:";
+pub const REDACTED: &str = "[REDACTED]";
+
+// how long `MemorySecretStore` keeps an execution's secrets, as long as finished runs are remembered, see `runs`
+const RETENTION_SECS: i64 = 7 * 24 * 3600;
+
+static STORE: Lazy<RwLock<Arc<dyn SecretStore>>> = Lazy::new(|| RwLock::new(Arc::new(MemorySecretStore::default())));
+
+// global values are keyed with a `GLOBAL:` prefix, e.g. `GLOBAL:secret:apiToken`
+pub fn is_secret_key(key: &str) -> bool {
+    key.strip_prefix("GLOBAL:").unwrap_or(key).starts_with(SECRET_PREFIX)
+}
+
+/// A copy of `value` with everything under a secret key replaced by `[REDACTED]`, including in per tag overrides
+pub fn redact(value: &Value) -> Value {
+    match value {
+        Value::Object(fields) => Value::Object(
+            fields
+                .iter()
+                .map(|(key, value)| {
+                    let value = if is_secret_key(key) { Value::String(String::from(REDACTED)) } else { redact(value) };
+                    (key.clone(), value)
+                })
+                .collect(),
+        ),
+        Value::Array(items) => Value::Array(items.iter().map(redact).collect()),
+        value => value.clone(),
+    }
+}
+
+pub(crate) fn serialize_redacted<S>(value: &Option<Value>, serializer: S) -> Result<S::Ok, S::Error>
+where
+    S: Serializer,
+{
+    value.as_ref().map(redact).serialize(serializer)
+}
+
+/// The string values under secret keys, to be masked wherever they turn up. Other values (numbers, booleans) are only
+/// redacted, masking them would mask every matching digit or word
+pub fn values(value: Option<&Value>) -> Vec<String> {
+    fn collect(value: &Value, secret: bool, values: &mut Vec<String>) {
+        match value {
+            Value::Object(fields) => {
+                for (key, value) in fields {
+                    collect(value, secret || is_secret_key(key), values);
+                }
+            }
+            Value::Array(items) => items.iter().for_each(|item| collect(item, secret, values)),
+            Value::String(string) if secret && !string.is_empty() && string != REDACTED => {
+                values.push(string.clone())
+            }
+            _ => {}
+        }
+    }
+
+    let mut values = vec![];
+    if let Some(value) = value {
+        collect(value, false, &mut values);
+    }
+    values
+}
+
+/// Replaces every occurrence of the secrets in the string values within `value`
+pub fn mask(value: &mut Value, secrets: &[String]) {
+    match value {
+        Value::String(string) => *string = mask_str(string, secrets),
+        Value::Array(items) => items.iter_mut().for_each(|item| mask(item, secrets)),
+        Value::Object(fields) => fields.values_mut().for_each(|value| mask(value, secrets)),
+        _ => {}
+    }
+}
+
+pub fn mask_str(string: &str, secrets: &[String]) -> String {
+    secrets
+        .iter()
+        .fold(string.to_string(), |masked, secret| masked.replace(secret.as_str(), REDACTED))
+}
+
+/// Copies the secrets in `source` into `target`, undoing `redact`
+pub fn restore(target: &mut Value, source: &Value) {
+    if let (Value::Object(target), Value::Object(source)) = (target, source) {
+        for (key, value) in source {
+            if is_secret_key(key) {
+                target.insert(key.clone(), value.clone());
+            } else if let Some(target) = target.get_mut(key) {
+                restore(target, value);
+            }
+        }
+    }
+}
+
+/// Only the entries under secret keys, along with the keys leading to them, none if there are no secrets
+pub fn extract(value: &Value) -> Option<Value> {
+    match value {
+        Value::Object(fields) => {
+            let secrets = fields
+                .iter()
+                .filter_map(|(key, value)| {
+                    if is_secret_key(key) {
+                        Some((key.clone(), value.clone()))
+                    } else {
+                        extract(value).map(|value| (key.clone(), value))
+                    }
+                })
+                .collect::<serde_json::Map<String, Value>>();
+            if secrets.is_empty() {
+                None
+            } else {
+                Some(Value::Object(secrets))
+            }
+        }
+        _ => None,
+    }
+}
+
+/// Whether `value` has secrets that were redacted and not restored since
+pub fn is_redacted(value: Option<&Value>) -> bool {
+    match value {
+        Some(Value::Object(fields)) => fields.iter().any(|(key, value)| {
+            (is_secret_key(key) && value.as_str() == Some(REDACTED)) || is_redacted(Some(value))
+        }),
+        _ => false,
+    }
+}
+
+/// A copy of the config with its secrets redacted, for storing or responding with
+pub fn redact_config(config: &WorkerConfig) -> WorkerConfig {
+    let mut config = config.clone();
+    config.global = config.global.as_ref().map(redact);
+    config.custom = config.custom.as_ref().map(redact);
+    config
+}
+
+/// The secrets of an execution's worker, kept apart from its record so they're only read to restore them
+#[derive(Serialize, Deserialize, Debug, Clone, MongoModel)]
+#[serde(rename_all = "camelCase")]
+pub struct ExecutionSecrets {
+    #[serde(rename = "_id", skip_serializing_if = "Option::is_none")]
+    pub id: Option<ObjectId>,
+    #[serde(rename = "SK")]
+    pub sort_key: String,
+    pub global: Option<Value>,
+    pub custom: Option<Value>,
+    pub updated_at: DateTime<Utc>,
+}
+
+impl ExecutionSecrets {
+    pub fn sort_key(execution_id: &Uuid) -> String {
+        format!("secrets#{}", execution_id)
+    }
+}
+
+/// Keeps executions' secrets, see `save`
+#[async_trait]
+pub trait SecretStore: fmt::Debug + Send + Sync {
+    /// Saves the execution's secrets, replacing any saved before
+    async fn save(&self, execution_id: Uuid, global: Option<Value>, custom: Option<Value>) -> Result<()>;
+    async fn load(&self, execution_id: Uuid) -> Result<Option<ExecutionSecrets>>;
+}
+
+/// Keeps secrets in memory, so they're only seen by this process and are lost on restart.
+#[derive(Debug, Default)]
+pub struct MemorySecretStore {
+    secrets: Mutex<HashMap<Uuid, ExecutionSecrets>>,
+}
+
+#[async_trait]
+impl SecretStore for MemorySecretStore {
+    async fn save(&self, execution_id: Uuid, global: Option<Value>, custom: Option<Value>) -> Result<()> {
+        let now = Utc::now();
+        let retention = chrono::Duration::seconds(RETENTION_SECS);
+        let mut secrets = self.secrets.lock();
+        secrets.retain(|_, saved| now - saved.updated_at < retention);
+        let saved = ExecutionSecrets {
+            id: None,
+            sort_key: ExecutionSecrets::sort_key(&execution_id),
+            global,
+            custom,
+            updated_at: now,
+        };
+        secrets.insert(execution_id, saved);
+        Ok(())
+    }
+
+    async fn load(&self, execution_id: Uuid) -> Result<Option<ExecutionSecrets>> {
+        Ok(self.secrets.lock().get(&execution_id).cloned())
+    }
+}
+
+/// Keeps secrets in the `ExecutionSecrets` collection, one document per execution
+#[derive(Clone)]
+pub struct MongoSecretStore {
+    db: MongoDbClient,
+}
+
+impl MongoSecretStore {
+    pub fn new(db: MongoDbClient) -> Self {
+        MongoSecretStore { db }
+    }
+}
+
+impl fmt::Debug for MongoSecretStore {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("MongoSecretStore").finish()
+    }
+}
+
+#[async_trait]
+impl SecretStore for MongoSecretStore {
+    async fn save(&self, execution_id: Uuid, global: Option<Value>, custom: Option<Value>) -> Result<()> {
+        let filter = doc! {"SK": ExecutionSecrets::sort_key(&execution_id)};
+        let update = doc! {"$set": {
+            "global": mongodb::bson::to_bson(&global)?,
+            "custom": mongodb::bson::to_bson(&custom)?,
+            "updatedAt": Utc::now().to_rfc3339(),
+        }};
+        self.db.upsert_item::<ExecutionSecrets>(filter, update).await?;
+        Ok(())
+    }
+
+    async fn load(&self, execution_id: Uuid) -> Result<Option<ExecutionSecrets>> {
+        let filter = doc! {"SK": ExecutionSecrets::sort_key(&execution_id)};
+        Ok(self.db.filter_item::<ExecutionSecrets>(Some(filter)).await?)
+    }
+}
+
+/// Replaces the store secrets are saved in, they're kept in memory until this is called
+pub fn set_store(store: Arc<dyn SecretStore>) {
+    *STORE.write() = store;
+}
+
+fn store() -> Arc<dyn SecretStore> {
+    STORE.read().clone()
+}
+
+/// Saves the secrets in the config an execution is starting with, so its runs can get them back once they've been
+/// redacted. Nothing is saved for a config without secrets
+pub async fn save(execution_id: Uuid, config: &WorkerConfig) -> Result<()> {
+    let global = config.global.as_ref().and_then(extract);
+    let custom = config.custom.as_ref().and_then(extract);
+    if global.is_none() && custom.is_none() {
+        return Ok(());
+    }
+    store().save(execution_id, global, custom).await
+}
+
+/// Puts the execution's saved secrets back into redacted global and custom values. Fails if they were redacted and
+/// weren't saved, or have since been forgotten
+pub async fn restore_values(execution_id: Uuid, global: Option<&mut Value>, custom: Option<&mut Value>) -> Result<()> {
+    if !is_redacted(global.as_deref()) && !is_redacted(custom.as_deref()) {
+        return Ok(());
+    }
+    let saved = match store().load(execution_id).await? {
+        Some(saved) => saved,
+        None => bail!("The secrets of execution {} weren't found, they can't be restored", execution_id),
+    };
+    if let (Some(global), Some(source)) = (global, saved.global.as_ref()) {
+        restore(global, source);
+    }
+    if let (Some(custom), Some(source)) = (custom, saved.custom.as_ref()) {
+        restore(custom, source);
+    }
+    Ok(())
+}
+
+/// Like `restore_values`, for a recorded execution's config that's being run again
+pub async fn restore_config(execution_id: Uuid, config: &mut WorkerConfig) -> Result<()> {
+    restore_values(execution_id, config.global.as_mut(), config.custom.as_mut()).await
+}
@@ -50,24 +50,12 @@ impl Conditional {
     }
 
     pub fn eval(&self) -> Result<bool> {
-        // **NOT short-circuiting**
-        // not all arms of the expression need to be evaluated,
-        // e.g. (a AND b AND c) if a is false, b and c do not need to be evaluated
-        // similarly for (a OR b OR c) if a is true, b and c do not need to be evaluated
-        // this applies between conditions and groups of conditions
-        // to skip unnecessary evaluation, we'd need a tree structure to represent the expression
-        // which are difficult to implement in Rust (technically not impossible, but prohibitively difficult)
-        // for now, we'll just evaluate the expression as a whole
-
-        let mut result = true;
-        for group in self.expression.iter() {
-            result = match group.op {
-                Some(Operator::And) => result && group.eval()?,
-                Some(Operator::Or) => result || group.eval()?,
-                None => group.eval()?,
-            };
-        }
-        return Ok(result);
+        // a group's operator joins it to the group before it. See `eval_with_precedence` for how AND and OR combine,
+        // the same rules are used for the conditions within each group
+        eval_with_precedence(
+            self.expression.iter().map(|group| (group.op.clone(), group)),
+            |group| group.eval(),
+        )
     }
 
     pub fn build_expression_str(&self) -> Result<String> {
@@ -76,26 +64,42 @@ impl Conditional {
             if let Some(operator) = &group.op {
                 expression_str.push_str(&format!(" {} ", operator.as_ref()));
             }
-            expression_str.push_str("(");
-            for condition in &group.conditions {
-                expression_str.push_str(
-                    &format!(
-                        "{} {} {}", 
-                        self.parse_operand(&condition.var1)?, 
-                        condition.comparitor.to_string(), 
-                        self.parse_operand(&condition.var2)?
-                    )
-                );
-                if let Some(operator) = &condition.op {
-                    expression_str.push_str(&format!(" {} ", operator.as_ref()));
-                }
-            }
-            expression_str.push_str(")");
+            expression_str.push_str(&self.build_group_str(group)?);
         }
 
         Ok(expression_str)
     }
 
+    // a group and the groups nested within it, each in parentheses. Operators are only written between operands, a
+    // missing one is written as the AND it's evaluated as
+    fn build_group_str(&self, group: &ConditionGroup) -> Result<String> {
+        let mut group_str = String::from("(");
+        for (index, condition) in group.conditions.iter().enumerate() {
+            group_str.push_str(
+                &format!(
+                    "{} {} {}", 
+                    self.parse_operand(&condition.var1)?, 
+                    condition.comparitor.to_string(), 
+                    self.parse_operand(&condition.var2)?
+                )
+            );
+            if index + 1 < group.conditions.len() {
+                let operator = condition.op.as_ref().unwrap_or(&Operator::And);
+                group_str.push_str(&format!(" {} ", operator.as_ref()));
+            }
+        }
+        for (index, nested) in group.groups.iter().enumerate() {
+            if index > 0 || !group.conditions.is_empty() {
+                let operator = nested.op.as_ref().unwrap_or(&Operator::And);
+                group_str.push_str(&format!(" {} ", operator.as_ref()));
+            }
+            group_str.push_str(&self.build_group_str(nested)?);
+        }
+        group_str.push_str(")");
+
+        Ok(group_str)
+    }
+
     fn parse_operator(&self, op: &str) -> Result<&str> {
         match op {
             "AND" => Ok("&&"),
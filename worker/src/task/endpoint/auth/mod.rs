@@ -5,8 +5,10 @@ use super::Endpoint;
 use http::StatusCode;
 use reqwest::header::{HeaderMap, HeaderName, HeaderValue};
 use serde::{Deserialize, Serialize};
+use crate::client::SendVia;
 use crate::WorkerInvocation;
 use async_trait::async_trait;
+use tracing::debug;
 
 #[derive(Debug, Serialize, Deserialize)]
 pub enum Auth {
@@ -19,6 +21,8 @@ pub enum Auth {
     Splunk(SplunkAuth),
     Dnac(DnacAuth),
     Viptela(ViptelaAuth),
+    PagerDuty(PagerDutyAuth),
+    Prometheus(PrometheusAuth),
 }
 
 impl Auth {
@@ -45,6 +49,10 @@ impl Auth {
                 password: viptela_integration.password.clone(),
                 v_manage_hostname: viptela_integration.v_manage_hostname.clone(),
             }),
+            Integration::PagerDuty(_) => Auth::PagerDuty(PagerDutyAuth {}),
+            Integration::Prometheus(prometheus_integration) => Auth::Prometheus(PrometheusAuth {
+                bearer_token: prometheus_integration.bearer_token.clone(),
+            }),
         }
     }
 }
@@ -66,6 +74,8 @@ impl InjectAuth for Auth {
             Auth::Splunk(splunk_auth) => splunk_auth.inject_auth(task, context).await,
             Auth::Dnac(dnac_auth) => dnac_auth.inject_auth(task, context).await,
             Auth::Viptela(viptela_auth) => viptela_auth.inject_auth(task, context).await,
+            Auth::PagerDuty(pagerduty_auth) => pagerduty_auth.inject_auth(task, context).await,
+            Auth::Prometheus(prometheus_auth) => prometheus_auth.inject_auth(task, context).await,
         };
     }
 }
@@ -190,7 +200,7 @@ impl InjectAuth for DnacAuth {
         let url = format!("https://{}/dna/system/api/v1/auth/token", self.dnac_hostname);
         let response = context.client.post(url)
             .basic_auth(&self.username, Some(&self.password))
-            .send().await.unwrap();
+            .send_via(context).await.unwrap();
 
         let token = match response.status() {
             StatusCode::OK => {
@@ -198,7 +208,8 @@ impl InjectAuth for DnacAuth {
                 response_body["Token"].as_str().unwrap().to_string()
             },
             _ => {
-                dbg!(&response.text().await);
+                let body = response.text().await;
+                debug!(?body, "DNAC token request failed");
                 panic!("Failed to get token from DNAC");
             }
         };
@@ -223,7 +234,7 @@ impl InjectAuth for ViptelaAuth {
         let payload = [("j_username", &self.username), ("j_password", &self.password)];
         let response = context.client.post(url)
             .form(&payload)
-            .send().await.unwrap();
+            .send_via(context).await.unwrap();
 
         let jsessionid = match response.headers().get("Set-Cookie") {
             Some(cookies) => {
@@ -238,7 +249,7 @@ impl InjectAuth for ViptelaAuth {
         let url = format!("https://{}/dataservice/client/token", self.v_manage_hostname);
         let response = context.client.get(url)
             .header("Cookie", jsessionid.clone())
-            .send().await
+            .send_via(context).await
             .unwrap();
 
         let token = match response.status() {
@@ -258,3 +269,30 @@ impl InjectAuth for ViptelaAuth {
         }
     }
 }
+
+// PagerDuty's Events API v2 doesn't authenticate with a header. The integration's routing key has to be sent in
+// the request body instead, which depends on the shape of the event the task is sending, so it can't be injected
+// here. Like every integration field, the routing key is available to the task as a substitution variable
+// ({{routingKey}}) when the task is rendered, so the body can reference it without the key being stored in the
+// worker config.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct PagerDutyAuth {}
+
+#[async_trait]
+impl InjectAuth for PagerDutyAuth {
+    async fn inject_auth(&mut self, task: &mut Endpoint, context: &WorkerInvocation) {}
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct PrometheusAuth {
+    bearer_token: Option<String>,
+}
+
+#[async_trait]
+impl InjectAuth for PrometheusAuth {
+    async fn inject_auth(&mut self, task: &mut Endpoint, context: &WorkerInvocation) {
+        if let Some(bearer_token) = &self.bearer_token {
+            task.add_header(String::from("Authorization"), format!("Bearer {}", bearer_token));
+        }
+    }
+}
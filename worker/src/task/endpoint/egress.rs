@@ -0,0 +1,181 @@
+// Limits the hosts endpoint tasks can send requests to. Target URLs are written by tenants and substituted at run
+// time, so without a limit a worker could reach services that are only meant to be reachable from inside our network,
+// such as a cloud provider's metadata service.
+//
+// Rules are hostnames (`api.meraki.com`, or `*.meraki.com` for its subdomains) or CIDR ranges (`10.0.0.0/8`). CIDR
+// ranges are matched against IP hosts and the addresses hostnames resolve to. A host matching the denylist is rejected
+// even if it's allowed, and once there's an allowlist only hosts matching it are allowed. Loopback, link-local and
+// unspecified addresses and metadata hostnames are always denied. Private networks are denied unless the allowlist
+// covers them, for workers that call on-premises devices.
+//
+// Hostnames are resolved through `EgressResolver` as they're connected to, so the addresses checked are the ones the
+// request goes to and a hostname can't resolve to an allowed address for the check and a denied one for the request.
+use anyhow::{bail, Result};
+use once_cell::sync::Lazy;
+use reqwest::dns::{Addrs, Name, Resolve, Resolving};
+use std::net::{IpAddr, SocketAddr};
+use url::{Host, Url};
+
+const DEFAULT_DENYLIST: &[&str] = &[
+    "127.0.0.0/8",
+    "::1/128",
+    "0.0.0.0/8",
+    "::/128",
+    "localhost",
+    "169.254.0.0/16",
+    "fe80::/10",
+    // AWS's metadata service over IPv6 and Alibaba Cloud's
+    "fd00:ec2::254/128",
+    "100.100.100.200/32",
+    "metadata.google.internal",
+];
+
+// RFC 1918 ranges and IPv6 unique local addresses
+const PRIVATE_NETWORKS: &[&str] = &["10.0.0.0/8", "172.16.0.0/12", "192.168.0.0/16", "fc00::/7"];
+
+// set as comma separated rules with the EGRESS_ALLOWLIST and EGRESS_DENYLIST env vars
+static RULES: Lazy<EgressRules> = Lazy::new(|| {
+    let rules = |var: &str| std::env::var(var).unwrap_or_default();
+    EgressRules::new(&rules("EGRESS_ALLOWLIST"), &rules("EGRESS_DENYLIST"))
+});
+
+#[derive(Debug, Clone, PartialEq)]
+enum Rule {
+    Host(String),
+    // the domain's subdomains, but not the domain itself
+    Subdomains(String),
+    Cidr(IpAddr, u8),
+}
+
+impl Rule {
+    fn parse(rule: &str) -> Option<Rule> {
+        let rule = rule.trim().trim_end_matches('.').to_lowercase();
+        if rule.is_empty() {
+            return None;
+        }
+        if let Some((addr, prefix)) = rule.split_once('/') {
+            let addr = addr.parse::<IpAddr>().ok()?;
+            let max_prefix = if addr.is_ipv4() { 32 } else { 128 };
+            let prefix = prefix.parse::<u8>().ok().filter(|prefix| *prefix <= max_prefix)?;
+            return Some(Rule::Cidr(addr, prefix));
+        }
+        if let Ok(addr) = rule.parse::<IpAddr>() {
+            let prefix = if addr.is_ipv4() { 32 } else { 128 };
+            return Some(Rule::Cidr(addr, prefix));
+        }
+        match rule.strip_prefix("*.") {
+            Some(domain) => Some(Rule::Subdomains(domain.to_string())),
+            None => Some(Rule::Host(rule)),
+        }
+    }
+
+    fn matches(&self, host: &str, addrs: &[IpAddr]) -> bool {
+        match self {
+            Rule::Host(name) => host == name,
+            Rule::Subdomains(domain) => host
+                .strip_suffix(domain.as_str())
+                .map_or(false, |subdomain| subdomain.ends_with('.')),
+            Rule::Cidr(network, prefix) => addrs.iter().any(|addr| in_network(*addr, *network, *prefix)),
+        }
+    }
+}
+
+fn in_network(addr: IpAddr, network: IpAddr, prefix: u8) -> bool {
+    // IPv4 addresses mapped into IPv6 are matched as the IPv4 address they are
+    let addr = match addr {
+        IpAddr::V6(v6) => v6.to_ipv4_mapped().map(IpAddr::V4).unwrap_or(addr),
+        v4 => v4,
+    };
+    match (addr, network) {
+        (IpAddr::V4(addr), IpAddr::V4(network)) => {
+            let mask = u32::MAX.checked_shl(32 - u32::from(prefix)).unwrap_or(0);
+            u32::from(addr) & mask == u32::from(network) & mask
+        }
+        (IpAddr::V6(addr), IpAddr::V6(network)) => {
+            let mask = u128::MAX.checked_shl(128 - u32::from(prefix)).unwrap_or(0);
+            u128::from(addr) & mask == u128::from(network) & mask
+        }
+        _ => false,
+    }
+}
+
+#[derive(Debug, Default)]
+pub(crate) struct EgressRules {
+    allow: Vec<Rule>,
+    deny: Vec<Rule>,
+    private: Vec<Rule>,
+}
+
+impl EgressRules {
+    // from comma separated rules, the default denylist is added to `deny`. Rules that can't be read are ignored
+    pub(crate) fn new(allow: &str, deny: &str) -> Self {
+        let parse = |rules: &str| rules.split(',').filter_map(Rule::parse).collect::<Vec<Rule>>();
+        let mut denied = DEFAULT_DENYLIST.iter().filter_map(|rule| Rule::parse(rule)).collect::<Vec<Rule>>();
+        denied.extend(parse(deny));
+        EgressRules {
+            allow: parse(allow),
+            deny: denied,
+            private: PRIVATE_NETWORKS.iter().filter_map(|rule| Rule::parse(rule)).collect(),
+        }
+    }
+
+    // `addrs` are the host's addresses, the host itself for an IP host
+    pub(crate) fn check(&self, host: &str, addrs: &[IpAddr]) -> Result<()> {
+        self.check_name(host)?;
+        if self.deny.iter().any(|rule| rule.matches(host, addrs)) {
+            bail!("Requests to {} are blocked, endpoint tasks can't call it", host);
+        }
+        let private = addrs.iter().any(|addr| {
+            let addr = &[*addr];
+            self.private.iter().any(|rule| rule.matches(host, addr))
+                && !self.allow.iter().any(|rule| rule.matches(host, addr))
+        });
+        if private {
+            bail!("Requests to {} are blocked, it's on a private network that isn't on the allowlist", host);
+        }
+        if !self.allow.is_empty() && !self.allow.iter().any(|rule| rule.matches(host, addrs)) {
+            bail!("Requests to {} are blocked, it isn't on the allowlist for endpoint tasks", host);
+        }
+        Ok(())
+    }
+
+    // the denylist's hostname rules, for a hostname that hasn't been resolved yet
+    pub(crate) fn check_name(&self, host: &str) -> Result<()> {
+        if self.deny.iter().any(|rule| rule.matches(host, &[])) {
+            bail!("Requests to {} are blocked, endpoint tasks can't call it", host);
+        }
+        Ok(())
+    }
+}
+
+/// Fails if the URL's host isn't one endpoint tasks are allowed to call, checked before sending the request and for
+/// every redirect. IP hosts are checked in full, hostnames only against the denylist's hostnames, their addresses are
+/// checked as they're resolved, see `EgressResolver`
+pub(crate) fn check(url: &Url) -> Result<()> {
+    match url.host() {
+        Some(Host::Ipv4(addr)) => RULES.check(&addr.to_string(), &[IpAddr::V4(addr)]),
+        Some(Host::Ipv6(addr)) => RULES.check(&addr.to_string(), &[IpAddr::V6(addr)]),
+        Some(Host::Domain(domain)) => RULES.check_name(&domain.trim_end_matches('.').to_lowercase()),
+        None => bail!("{} has no host", url),
+    }
+}
+
+/// Resolves the hostnames endpoint requests connect to, failing for hosts the egress rules don't allow. The request
+/// connects to the addresses that were checked, so changing what a hostname resolves to between the check and the
+/// connection (DNS rebinding) doesn't get around the rules
+#[derive(Debug, Default)]
+pub(crate) struct EgressResolver;
+
+impl Resolve for EgressResolver {
+    fn resolve(&self, name: Name) -> Resolving {
+        Box::pin(async move {
+            let host = name.as_str().trim_end_matches('.').to_lowercase();
+            // the port is replaced with the request's own when connecting
+            let addrs = tokio::net::lookup_host((host.as_str(), 0)).await?.collect::<Vec<SocketAddr>>();
+            let ips = addrs.iter().map(SocketAddr::ip).collect::<Vec<IpAddr>>();
+            RULES.check(&host, &ips).map_err(|err| err.to_string())?;
+            let addrs: Addrs = Box::new(addrs.into_iter());
+            Ok(addrs)
+        })
+    }
+}
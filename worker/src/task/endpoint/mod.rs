@@ -1,19 +1,27 @@
 pub mod auth;
-use anyhow::{Result, bail};
+pub mod egress;
+use anyhow::{anyhow, Result, bail};
 use handlebars::Handlebars;
 use http::Method;
+use once_cell::sync::Lazy;
 use regex::Regex;
-use reqwest::header::{HeaderMap, HeaderName, HeaderValue};
+use base64::Engine;
+use reqwest::header::{HeaderMap, HeaderName, HeaderValue, ACCEPT_ENCODING, CONTENT_TYPE, LOCATION, USER_AGENT};
+use reqwest::multipart::{Form, Part};
+use reqwest::{Request, StatusCode};
 use serde::{Deserialize, Serialize};
 use serde_json::{Value, json};
 use std::collections::HashMap;
-use std::iter::{FromIterator};
+use std::fmt;
 use std::str::FromStr;
+use std::time::{Duration, Instant};
+use tracing::{debug, warn};
 use url::Url;
 use uuid::Uuid;
-use xpertly_common::{Header, Integration};
+use xpertly_common::{Header, Integration, MultipartPart, PartEncoding};
 
-use crate::{WorkerInvocation};
+use crate::client::SendVia;
+use crate::{Event, WorkerInvocation};
 use auth::InjectAuth;
 
 // #[derive(Serialize, Deserialize, Debug, Clone)]
@@ -58,7 +66,48 @@ use auth::InjectAuth;
 //     next: Next
 // }
 
-#[derive(Serialize, Deserialize, Debug, Clone)]
+// target URLs with this host are rewritten against the integration's base URL, see `Integration::base_url`
+const BASE_URL_PLACEHOLDER_HOST: &str = "base-url";
+
+const DEFAULT_SLOW_REQUEST_THRESHOLD_MS: u64 = 10_000;
+
+// requests taking longer than this are logged as slow, set with the SLOW_REQUEST_THRESHOLD_MS env var
+static SLOW_REQUEST_THRESHOLD: Lazy<Duration> = Lazy::new(|| {
+    let threshold = std::env::var("SLOW_REQUEST_THRESHOLD_MS")
+        .ok()
+        .and_then(|threshold| threshold.parse::<u64>().ok())
+        .filter(|threshold| *threshold > 0)
+        .unwrap_or(DEFAULT_SLOW_REQUEST_THRESHOLD_MS);
+    Duration::from_millis(threshold)
+});
+
+const DEFAULT_MULTIPART_MAX_BYTES: usize = 50 * 1024 * 1024;
+
+// most a multipart body's parts may add up to once decoded, set with the MULTIPART_MAX_BYTES env var
+static MULTIPART_MAX_BYTES: Lazy<usize> = Lazy::new(|| {
+    std::env::var("MULTIPART_MAX_BYTES")
+        .ok()
+        .and_then(|max| max.parse::<usize>().ok())
+        .filter(|max| *max > 0)
+        .unwrap_or(DEFAULT_MULTIPART_MAX_BYTES)
+});
+
+// headers never forwarded by webhook tasks, so internal credentials don't leak to third-party receivers. Set as a comma
+// separated list of header names with the WEBHOOK_HEADER_DENYLIST env var
+static WEBHOOK_HEADER_DENYLIST: Lazy<Vec<String>> = Lazy::new(|| {
+    std::env::var("WEBHOOK_HEADER_DENYLIST")
+        .map(|denylist| {
+            denylist
+                .split(',')
+                .map(|name| name.trim().to_lowercase())
+                .filter(|name| !name.is_empty())
+                .collect()
+        })
+        .unwrap_or_default()
+});
+
+// Debug is implemented below so credentials aren't printed when a task is logged
+#[derive(Serialize, Deserialize, Clone)]
 #[serde(rename_all = "camelCase")]
 pub struct Endpoint {
     pub(crate) vendor: String,
@@ -68,8 +117,55 @@ pub struct Endpoint {
     pub(crate) headers: Option<Vec<Header>>,
     pub(crate) path_params: Option<HashMap<String, String>>,
     pub(crate) query_params: Option<HashMap<String, String>>,
+    // see `EndpointFields::send_empty_query_params`
+    #[serde(default)]
+    pub(crate) send_empty_query_params: bool,
     pub(crate) body: Option<Value>,
+    // sent as multipart/form-data in place of `body`, see `MultipartPart`
+    #[serde(default)]
+    pub(crate) multipart: Option<Vec<MultipartPart>>,
     pub(crate) target_url: String,
+    #[serde(default)]
+    pub(crate) response_path: Option<String>,
+}
+
+// injected auth headers, query params like API tokens and the integration itself all hold credentials, so they're
+// masked. The body is printed with values under credential-like keys masked
+impl fmt::Debug for Endpoint {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let headers = self.headers.as_ref().map(|headers| {
+            headers
+                .iter()
+                .map(|header| (header.key.as_str(), redact(&header.key, &header.value)))
+                .collect::<Vec<(&str, &str)>>()
+        });
+        let query_params = self.query_params.as_ref().map(|query_params| {
+            query_params
+                .iter()
+                .map(|(key, value)| (key.as_str(), redact(key, value)))
+                .collect::<HashMap<&str, &str>>()
+        });
+        f.debug_struct("Endpoint")
+            .field("vendor", &self.vendor)
+            .field("integration_id", &self.integration_id)
+            .field("integration", &self.integration.as_ref().map(|_| REDACTED))
+            .field("method", &self.method)
+            .field("headers", &headers)
+            .field("path_params", &self.path_params)
+            .field("query_params", &query_params)
+            .field("body", &self.body.as_ref().map(redact_json))
+            // part values can be whole files
+            .field(
+                "multipart",
+                &self
+                    .multipart
+                    .as_ref()
+                    .map(|parts| parts.iter().map(|part| part.name.as_str()).collect::<Vec<&str>>()),
+            )
+            .field("target_url", &self.target_url)
+            .field("response_path", &self.response_path)
+            .finish()
+    }
 }
 
 impl Endpoint {
@@ -97,31 +193,58 @@ impl Endpoint {
         }
     }
 
-    fn convert_headers(&self) -> Option<HeaderMap> {
-        let headers = &self.headers;
-        if let Some(headers) = headers {
-            let tuple_headers: Vec<(String, String)> =
-                headers.iter().map(|header| header.as_tuple()).collect();
-            let converted_headers: Vec<(HeaderName, HeaderValue)> = tuple_headers
-                .iter()
-                .map(|header| {
-                    (
-                        HeaderName::from_str(&header.0).unwrap(),
-                        HeaderValue::from_str(&header.1).unwrap(),
-                    )
-                })
-                .collect();
-            let headers = HeaderMap::from_iter(converted_headers);
-            Some(headers)
-        } else {
-            None
+    // removes the headers named in `denylist`, matched case-insensitively, returning the names of those removed
+    pub(crate) fn strip_headers(&mut self, denylist: &[String]) -> Vec<String> {
+        let mut stripped = vec![];
+        if let Some(headers) = &mut self.headers {
+            headers.retain(|header| {
+                let denied = denylist.iter().any(|name| name.eq_ignore_ascii_case(header.key.trim()));
+                if denied {
+                    stripped.push(header.key.clone());
+                }
+                !denied
+            });
         }
+        stripped
     }
 
-    fn convert_query_params(&self) -> Option<Vec<(String, String)>> {
+    // applies the webhook header denylist before the webhook is sent
+    pub(crate) fn strip_webhook_headers(&mut self) {
+        let stripped = self.strip_headers(&WEBHOOK_HEADER_DENYLIST);
+        if !stripped.is_empty() {
+            warn!(target_url = %self.target_url, headers = ?stripped, "stripped denylisted headers from webhook");
+        }
+    }
+
+    // header values can be rendered from earlier outputs, so a value that isn't a valid header fails the task
+    // rather than panicking
+    fn convert_headers(&self) -> Result<HeaderMap> {
+        let mut converted_headers = HeaderMap::new();
+        if let Some(headers) = &self.headers {
+            for (key, value) in headers.iter().map(|header| header.as_tuple()) {
+                let name = match HeaderName::from_str(&key) {
+                    Ok(name) => name,
+                    Err(_) => bail!("Invalid header name: {}", key),
+                };
+                let value = match HeaderValue::from_str(&value) {
+                    Ok(value) => value,
+                    Err(_) => bail!("Invalid value for header {}", key),
+                };
+                converted_headers.append(name, value);
+            }
+        }
+        Ok(converted_headers)
+    }
+
+    // params whose value rendered empty, or as `undefined` for a variable with no value, are dropped unless the task
+    // sends empty params
+    pub(crate) fn convert_query_params(&self) -> Option<Vec<(String, String)>> {
         if let Some(params) = &self.query_params {
             let tuple_params = params
                 .iter()
+                .filter(|(_, value)| {
+                    self.send_empty_query_params || !(value.trim().is_empty() || value.trim() == "undefined")
+                })
                 .map(|(key, value)| (String::from(key), String::from(value)))
                 .collect::<Vec<(String, String)>>();
             Some(tuple_params)
@@ -130,7 +253,7 @@ impl Endpoint {
         }
     }
 
-    fn convert_url(&self, integration: Option<&Integration>, context: &WorkerInvocation) -> String {
+    pub(crate) fn convert_url(&self, integration: Option<&Integration>, context: &WorkerInvocation) -> String {
         if let Some(path_params) = &self.path_params {
             let url = &self.target_url;
             let re = Regex::new(r":([^/]+)").unwrap();
@@ -141,29 +264,64 @@ impl Endpoint {
             if let Some(integration) = integration {
                 let integration_json = serde_json::to_value(integration).unwrap();
                 integration_json.as_object().unwrap().iter().for_each(|(key, value)| {
-                    substitutions.insert(key.to_string(), value.to_string());
+                    // strings are substituted as they are, `to_string` would keep their JSON quotes
+                    let value = match value {
+                        Value::String(value) => value.clone(),
+                        value => value.to_string(),
+                    };
+                    substitutions.insert(key.to_string(), value);
                 });
             }
             
             let subbed = handlebars
                 .render_template(&handlebarred, &substitutions)
                 .unwrap();
-            dbg!(&subbed);
+            debug!(url = %subbed, "substituted path params");
             subbed
         } else {
             String::from(&self.target_url)
         }
     }
 
+    // rewrites a target URL using the placeholder host against the integration's base URL
+    pub(crate) fn resolve_base_url(&self, url: Url) -> Result<Url> {
+        if url.host_str() != Some(BASE_URL_PLACEHOLDER_HOST) {
+            return Ok(url);
+        }
+        let base_url = match self.integration.as_ref().and_then(|integration| integration.base_url()) {
+            Some(base_url) => base_url,
+            None => bail!(
+                "Target URL uses the {} placeholder host but the integration has no base URL",
+                BASE_URL_PLACEHOLDER_HOST
+            ),
+        };
+
+        let mut resolved = Url::parse(base_url)?;
+        let path = format!("{}{}", resolved.path().trim_end_matches('/'), url.path());
+        resolved.set_path(&path);
+        resolved.set_query(url.query());
+        Ok(resolved)
+    }
+
     pub fn get_auth(&self, integration: &Integration) -> auth::Auth {
         auth::Auth::new(integration)
     }
 
-    pub async fn get_integration(&self, context: &WorkerInvocation) -> Option<Integration> {
+    /// The integration the endpoint authenticates with, fetched once per run. Endpoints without an integration ID
+    /// don't have one
+    pub async fn get_integration(&self, context: &WorkerInvocation) -> Result<Option<Integration>> {
         let vendor = &self.vendor;
-        let integration_id = &self.integration_id.unwrap();
+        let integration_id = match &self.integration_id {
+            Some(integration_id) => integration_id,
+            None => return Ok(None),
+        };
+        let cached = context.integrations.lock().get(integration_id).cloned();
+        if let Some(integration) = cached {
+            return Ok(Some(integration));
+        }
+
         let url = format!("http://localhost:8000/api/tenants/{tenant_id}/integrations/{vendor}/{integration_id}", tenant_id=context.tenant_id, vendor=vendor, integration_id=integration_id);
-        dbg!(&url);
+        debug!(%url, "fetching integration");
         let response = context
         .client
         .get(Url::parse(&url).unwrap())
@@ -171,29 +329,37 @@ impl Endpoint {
             HeaderName::from_str("Authorization").unwrap(),
             HeaderValue::from_str(&context.auth_token).unwrap(),
         )
-        .send()
-        .await
-        .unwrap();
-        let integration_json = response.json::<serde_json::Value>().await.unwrap();
-        dbg!(&integration_json);
+        .send_via(context)
+        .await?;
+        let integration_json = response.json::<serde_json::Value>().await?;
+        debug!(integration_json = ?redact_json(&integration_json), "integration response");
         let integration = Integration::new(integration_json);
         if let Ok(integration) = integration {
-            dbg!(&integration);
-            Some(integration)
+            debug!(%integration_id, %vendor, "integration found");
+            context
+                .integrations
+                .lock()
+                .insert(*integration_id, integration.clone());
+            Ok(Some(integration))
         } else {
-            dbg!("Integration not found");
-            None
+            debug!("integration not found");
+            Ok(None)
         }
     }
 
     pub async fn prepare(&mut self, context: &WorkerInvocation) -> Result<()> {
-        let integration = self.get_integration(context).await;
+        // only webhooks run without an integration, and they're never prepared
+        let integration_id = match self.integration_id {
+            Some(integration_id) => integration_id,
+            None => bail!("Endpoint task for {} has no integration, only webhook tasks can run without one", self.target_url),
+        };
+        let integration = self.get_integration(context).await?;
         if let Some(integration) = integration.as_ref() {
             let mut auth = self.get_auth(integration);
             auth.inject_auth(self, context).await;
-            dbg!(&self);
+            debug!(task = ?self, "injected integration auth");
         } else {
-            bail!("Integration not found");
+            bail!("Integration {} not found", integration_id);
         }
         self.integration = integration;
 
@@ -203,6 +369,15 @@ impl Endpoint {
         Ok(())
     }
 
+    // the integration's User-Agent, falling back to the worker's. Without either the client's global one is sent, and
+    // a User-Agent header on the task beats all of them, see `Integration::user_agent`
+    pub(crate) fn user_agent<'a>(&'a self, worker_user_agent: Option<&'a str>) -> Option<&'a str> {
+        self.integration
+            .as_ref()
+            .and_then(|integration| integration.user_agent())
+            .or(worker_user_agent)
+    }
+
     pub async fn execute(&mut self, context: &WorkerInvocation) -> Result<serde_json::Value> {
         // let integration = self.get_integration(context).await;
         // if let Some(integration) = integration.as_ref() {
@@ -214,34 +389,220 @@ impl Endpoint {
         // }
 
         // let converted = self.convert_url(integration.as_ref(), context);
-        let url = Url::parse(&self.target_url)?;
-        let method;
-        let body;
+        let url = self.resolve_base_url(Url::parse(&self.target_url)?)?;
+        egress::check(&url)?;
+        let method = Method::from_str(&self.method)?;
 
-        // scoping the mutex lock so it isn't held across the await below
-        {
-            method = Method::from_str(&self.method)?;
-            body = self.body.clone();
+        let mut headers = self.convert_headers()?;
+        // a configured Content-Type would clash with the form's own, which carries the boundary between parts
+        if self.multipart.is_some() {
+            headers.remove(CONTENT_TYPE);
+        }
+        // the client negotiates gzip/deflate itself and only decompresses responses to its own Accept-Encoding,
+        // so a user supplied one would leave compressed bytes for the JSON parser
+        headers.remove(ACCEPT_ENCODING);
+        if !headers.contains_key(USER_AGENT) {
+            if let Some(user_agent) = self.user_agent(context.worker.user_agent.as_deref()) {
+                headers.insert(USER_AGENT, HeaderValue::from_str(user_agent)?);
+            }
         }
 
-        let response = context
+        let mut request = context
             .client
             .request(method, url)
-            .headers(self.convert_headers().unwrap())
-            .json(&body)
-            .query(&self.convert_query_params().unwrap())
-            .send()
-            .await?;
+            .headers(headers)
+            .query(&self.convert_query_params().unwrap_or_default());
+        // the body is sent as is, whatever its top-level type, e.g. the array of actions for a Meraki action batch.
+        // Tasks without one send no body rather than `null`
+        if let Some(parts) = &self.multipart {
+            request = request.multipart(multipart_form(parts)?);
+        } else if let Some(body) = &self.body {
+            request = request.json(body);
+        }
+        let request = request.build()?;
+        let request_details = if context.worker.debug_requests {
+            Some(request_details(&request))
+        } else {
+            None
+        };
+        let method = request.method().clone();
+        let url = request.url().clone();
+        let started = Instant::now();
+        let response = context.http_client.execute_endpoint(request).await?;
+        let elapsed = started.elapsed();
+        if elapsed > *SLOW_REQUEST_THRESHOLD {
+            warn!(%method, %url, elapsed_ms = elapsed.as_millis() as u64, "slow endpoint request");
+            let reason = format!(
+                "{} {} took {}ms, over the {}ms threshold",
+                method,
+                url,
+                elapsed.as_millis(),
+                SLOW_REQUEST_THRESHOLD.as_millis()
+            );
+            context.log_with_reason(Event::TaskSlow, None, None, Some(reason)).await;
+        }
 
-        dbg!(&response);
+        debug!(status = %response.status(), headers = ?redact_headers(response.headers()), "endpoint response");
 
         let status = response.status();
-        let response_json = response.json::<serde_json::Value>().await?;
-        dbg!(&response_json);
-        let result = json!({
+        // redirects aren't followed by default (see `client::build_client`), vendors mostly redirect API calls to a
+        // login page when the credentials are wrong
+        if status.is_redirection() {
+            let location = response
+                .headers()
+                .get(LOCATION)
+                .and_then(|location| location.to_str().ok())
+                .unwrap_or("nowhere");
+            bail!(
+                "{} {} was redirected ({}) to {}, check the integration's credentials",
+                method,
+                url,
+                status,
+                location
+            );
+        }
+        let response_json = response.json::<serde_json::Value>().await.map_err(|err| {
+            if status == StatusCode::UNAUTHORIZED || status == StatusCode::FORBIDDEN {
+                anyhow!("{} {} responded {}, check the integration's credentials", method, url, status)
+            } else {
+                anyhow!(err)
+            }
+        })?;
+        debug!(response = ?redact_json(&response_json), "endpoint response body");
+        let response_json = match self.response_path.as_deref() {
+            Some(path) if !path.is_empty() => match response_json.pointer(path) {
+                Some(subtree) => subtree.clone(),
+                None => bail!("Response path {} not found in response", path),
+            },
+            _ => response_json,
+        };
+        let mut result = json!({
             "statusCode": status.as_u16(),
             "response": response_json
         });
+        if let Some(request_details) = request_details {
+            result["request"] = request_details;
+        }
         Ok(result)
     }
 }
+
+// builds the multipart/form-data body from rendered parts, decoding base64 ones
+fn multipart_form(parts: &[MultipartPart]) -> Result<Form> {
+    let mut form = Form::new();
+    let mut total_bytes = 0;
+    for part in parts {
+        // what a variable renders as when the output or asset it references doesn't exist
+        if part.value == "undefined" {
+            bail!("Multipart part {} references a value that isn't available", part.name);
+        }
+        let bytes = match part.encoding {
+            PartEncoding::Text => part.value.clone().into_bytes(),
+            PartEncoding::Base64 => base64::engine::general_purpose::STANDARD
+                .decode(part.value.trim())
+                .map_err(|err| anyhow!("Multipart part {} isn't valid base64: {}", part.name, err))?,
+        };
+        total_bytes += bytes.len();
+        if total_bytes > *MULTIPART_MAX_BYTES {
+            bail!(
+                "Multipart body is over the {} byte limit at part {}",
+                *MULTIPART_MAX_BYTES,
+                part.name
+            );
+        }
+
+        let mut form_part = Part::bytes(bytes);
+        if let Some(file_name) = &part.file_name {
+            form_part = form_part.file_name(file_name.clone());
+        }
+        if let Some(content_type) = &part.content_type {
+            form_part = form_part.mime_str(content_type)?;
+        }
+        form = form.part(part.name.clone(), form_part);
+    }
+    Ok(form)
+}
+
+// header names that carry credentials, matched case-insensitively against the whole name or a part of it. Also used
+// for query params and JSON keys
+const REDACTED_HEADERS: &[&str] = &["authorization", "cookie", "key", "token", "secret", "password"];
+const REDACTED: &str = "[REDACTED]";
+
+fn is_credential(name: &str) -> bool {
+    let name = name.to_lowercase();
+    REDACTED_HEADERS.iter().any(|redacted| name.contains(redacted))
+}
+
+// `value`, unless `name` looks like it holds a credential
+fn redact<'a>(name: &str, value: &'a str) -> &'a str {
+    if is_credential(name) {
+        REDACTED
+    } else {
+        value
+    }
+}
+
+fn redact_headers(headers: &HeaderMap) -> serde_json::Map<String, Value> {
+    headers
+        .iter()
+        .map(|(name, value)| {
+            let value = String::from_utf8_lossy(value.as_bytes());
+            (name.to_string(), Value::String(redact(name.as_str(), &value).to_string()))
+        })
+        .collect()
+}
+
+// a copy of `value` with everything under credential-like keys masked, e.g. the token in an auth response
+pub(crate) fn redact_json(value: &Value) -> Value {
+    match value {
+        Value::Object(entries) => Value::Object(
+            entries
+                .iter()
+                .map(|(key, value)| {
+                    let value = if is_credential(key) {
+                        Value::String(String::from(REDACTED))
+                    } else {
+                        redact_json(value)
+                    };
+                    (key.clone(), value)
+                })
+                .collect(),
+        ),
+        Value::Array(items) => Value::Array(items.iter().map(redact_json).collect()),
+        other => other.clone(),
+    }
+}
+
+// `url` with credential-like query params and any password masked, e.g. an api_key passed in the query string
+fn redact_url(url: &Url) -> String {
+    let mut redacted = url.clone();
+    if url.query().is_some() {
+        let pairs = url
+            .query_pairs()
+            .map(|(name, value)| (name.to_string(), redact(&name, &value).to_string()))
+            .collect::<Vec<_>>();
+        redacted.query_pairs_mut().clear().extend_pairs(pairs);
+    }
+    if url.password().is_some() {
+        let _ = redacted.set_password(Some(REDACTED));
+    }
+    redacted.to_string()
+}
+
+// the method, URL, headers and body of a request as sent, with credentials redacted
+pub(crate) fn request_details(request: &Request) -> Value {
+    let headers = redact_headers(request.headers());
+    let body = request
+        .body()
+        .and_then(|body| body.as_bytes())
+        .and_then(|bytes| serde_json::from_slice(bytes).ok())
+        .map(|body| redact_json(&body))
+        .unwrap_or(Value::Null);
+
+    json!({
+        "method": request.method().as_str(),
+        "url": redact_url(request.url()),
+        "headers": headers,
+        "body": body
+    })
+}
@@ -1,13 +1,14 @@
 use std::collections::HashMap;
 
-use super::Task;
+use super::{custom_output_key, Task};
 use crate::WorkerInvocation;
-use anyhow::Result;
+use anyhow::{bail, Result};
 use regex::Regex;
 use serde::{Deserialize, Serialize};
 use serde_json::Value;
 use serde_json::json;
 use tera::Tera;
+use tracing::{debug, warn};
 use url::quirks::search;
 use xpertly_common::*;
 
@@ -19,6 +20,58 @@ pub struct Filter {
     pub search_key: String,
     pub search_value: String,
     pub condition: String,
+    // when set, only the value at this JSON pointer is matched and returned
+    pub pointer: Option<String>,
+}
+
+impl Filter {
+    /// Conditions `matches` can match on, anything else matches nothing
+    pub const CONDITIONS: [&'static str; 8] = ["=", "!=", "contains", "startsWith", ">", ">=", "<", "<="];
+}
+
+// vendor APIs often return numeric values as strings (e.g. "speed": "50000000"),
+// so numeric strings are compared as numbers as well
+fn as_number(value: &Value) -> Option<f64> {
+    match value {
+        Value::Number(number) => number.as_f64(),
+        Value::String(string) => string.trim().parse::<f64>().ok(),
+        _ => None,
+    }
+}
+
+// whether `value` meets `condition` against the task's search value
+fn matches(value: &Value, search_value: &str, condition: &str) -> bool {
+    match condition {
+        "=" => value == search_value,
+        "!=" => value != search_value,
+        "contains" => match value {
+            Value::String(string) => string.contains(search_value),
+            Value::Array(arr) => arr.iter().any(|item| item == search_value),
+            Value::Object(obj) => obj.contains_key(search_value),
+            _ => false,
+        },
+        "startsWith" => match value.as_str() {
+            Some(val) => val.starts_with(search_value),
+            None => false,
+        },
+        // a value or search value that isn't a number can't be ordered, so it doesn't match
+        ">" | ">=" | "<" | "<=" => {
+            let (val, search_val) = match (as_number(value), search_value.trim().parse::<f64>()) {
+                (Some(val), Ok(search_val)) => (val, search_val),
+                _ => return false,
+            };
+            match condition {
+                ">" => val > search_val,
+                ">=" => val >= search_val,
+                "<" => val < search_val,
+                _ => val <= search_val,
+            }
+        }
+        _ => {
+            warn!(condition, "filter condition not supported");
+            false
+        }
+    }
 }
 
 fn search_json(
@@ -32,102 +85,11 @@ fn search_json(
 ) {
     match json_obj {
         Value::Object(ref obj) => {
-            if obj.contains_key(&search_key) {
-                match condition {
-                    "=" => {
-                        // let keys: Vec<&String> = obj.keys().collect();
-                        // dbg!(keys);
-                        if search_value == obj[&search_key] {
-                            match parent {
-                                Some(ref par) => response.push(par.clone()),
-                                None => response.push(json_obj.clone()),
-                            }
-                        }
-                    }
-                    "!=" => {
-                        if search_value != obj[&search_key] {
-                            match parent {
-                                Some(ref par) => response.push(par.clone()),
-                                None => response.push(json_obj.clone()),
-                            }
-                        }
-                    }
-                    "contains" => {
-                        let var = obj.get(&search_key);
-                        if let Some(var) = var {
-                            match var {
-                                Value::String(string) => {
-                                    if string.contains(&search_value) {
-                                        match parent {
-                                            Some(ref par) => response.push(par.clone()),
-                                            None => response.push(json_obj.clone()),
-                                        }
-                                    }
-                                }
-                                Value::Array(arr) => {
-                                    for item in arr {
-                                        if item.eq(&search_value) {
-                                            match parent {
-                                                Some(ref par) => response.push(par.clone()),
-                                                None => response.push(json_obj.clone()),
-                                            }
-                                        }
-                                    }
-                                },
-                                Value::Object(obj) => {
-                                    if obj.contains_key(&search_value) {
-                                        match parent {
-                                            Some(ref par) => response.push(par.clone()),
-                                            None => response.push(json_obj.clone()),
-                                        }
-                                    }
-                                }
-                                _ => {}
-                            }
-                        }
-                    }
-                    "startsWith" => {
-                        if let Some(val) = obj[&search_key].as_str() {
-                            if val.starts_with(&search_value) {
-                                match parent {
-                                    Some(ref par) => response.push(par.clone()),
-                                    None => response.push(json_obj.clone()),
-                                }
-                            }
-                        }
-                    }
-                    ">" => {
-                        if let Some(val) = obj[&search_key].as_f64() {
-                            let search_val = match search_value.parse::<f64>() {
-                                Ok(val) => val,
-                                _ => 0.0,
-                            };
-
-                            if val > search_val {
-                                match parent {
-                                    Some(ref par) => response.push(par.clone()),
-                                    None => response.push(json_obj.clone()),
-                                }
-                            }
-                        }
-                    }
-                    "<" => {
-                        if let Some(val) = obj[&search_key].as_f64() {
-                            let search_val = match search_value.parse::<f64>() {
-                                Ok(val) => val,
-                                _ => 0.0,
-                            };
-
-                            if val < search_val {
-                                match parent {
-                                    Some(ref par) => response.push(par.clone()),
-                                    None => response.push(json_obj.clone()),
-                                }
-                            }
-                        }
-                    }
-                    _ => {
-                        println!("Condition not supported: {}", condition);
+            if let Some(value) = obj.get(&search_key) {
+                if matches(value, &search_value, condition) {
+                    match parent {
+                        Some(ref par) => response.push(par.clone()),
+                        None => response.push(json_obj.clone()),
                     }
                 }
             }
@@ -171,6 +133,8 @@ impl Filter {
             .map(|(react_id, task)| (task.name.clone(), react_id.clone()))
             .collect::<HashMap<String, String>>();
         let variable_re = Regex::new(r"\{\{((?P<var_type>[^:\{\}]*):)?(?P<var_identifier>[^\[\.\{\}]+)\.?(?P<var_path>[^\}\{]*)\}\}").unwrap();
+        // the replacer can't return an error, so an unknown task is recorded and reported once replacing is done
+        let mut unknown_task: Option<String> = None;
         let object_key = variable_re
             .replace(&self.object_to_filter, |groups: &regex::Captures| {
                 let var_type = groups.name("var_type");
@@ -195,12 +159,24 @@ impl Filter {
                 match var_type {
                     Some(var_type) => match var_type.as_str() {
                         "OUTPUT" => {
-                            let task_id = task_name_map.get(&var_identifier).unwrap();
-                            format!(
-                                "{{{{output.{}{} | json_encode() }}}}",
-                                task_id,
-                                tokens.join("")
-                            )
+                            let task_id = match task_name_map.get(&var_identifier) {
+                                Some(task_id) => task_id.clone(),
+                                None => {
+                                    unknown_task = Some(var_identifier.clone());
+                                    String::from("default")
+                                }
+                            };
+                            // the custom output of a task whose output isn't an object is stored next to it
+                            let custom_key = custom_output_key(&task_id);
+                            match tokens.split_first() {
+                                Some((first, rest))
+                                    if first == "['customOutput']"
+                                        && context.outputs.lock().contains_key(&custom_key) =>
+                                {
+                                    format!("{{{{output['{}']{} | json_encode() }}}}", custom_key, rest.join(""))
+                                }
+                                _ => format!("{{{{output.{}{} | json_encode() }}}}", task_id, tokens.join("")),
+                            }
                         }
                         "ASSET" => {
                             format!(
@@ -226,20 +202,45 @@ impl Filter {
                 }
             })
             .to_string();
+        if let Some(task_name) = unknown_task {
+            bail!("unknown task referenced in filter: {}", task_name);
+        }
 
         let mut tera_context = tera::Context::new();
-        tera_context.insert("output", &context.outputs.lock().unwrap().clone());
-        let rendered = Tera::one_off(&object_key, &tera_context, false).unwrap();
-        self.json_obj = Some(serde_json::from_str::<Value>(&rendered).unwrap());
+        tera_context.insert("output", &context.outputs.lock().clone());
+        // a source referencing an output that hasn't been produced (yet) fails to render, or renders to nothing when
+        // the output is there but the referenced value is empty
+        let rendered = match Tera::one_off(&object_key, &tera_context, false) {
+            Ok(rendered) => rendered,
+            Err(err) => {
+                debug!(?err, "failed to render filter source");
+                bail!("filter source could not be resolved: {}", self.object_to_filter);
+            }
+        };
+        if matches!(rendered.trim(), "" | "undefined" | "null") {
+            bail!("filter source could not be resolved: {}", self.object_to_filter);
+        }
+        match serde_json::from_str::<Value>(&rendered) {
+            Ok(json_obj) => self.json_obj = Some(json_obj),
+            Err(err) => bail!("filter source {} isn't valid JSON: {}", self.object_to_filter, err),
+        }
 
         Ok(())
     }
 
     pub async fn execute(&self, context: &WorkerInvocation) -> Value {
-        dbg!("Filtering object: ", &self.object_to_filter);
+        debug!(object = %self.object_to_filter, "filtering object");
         let mut res: Vec<Value> = vec![];
-        if let Some(json_obj) = &self.json_obj {
-            search_json(
+        match (&self.json_obj, &self.pointer) {
+            // a pointer that doesn't resolve matches nothing
+            (Some(json_obj), Some(pointer)) => {
+                if let Some(value) = json_obj.pointer(pointer) {
+                    if matches(value, &self.search_value, &self.condition) {
+                        res.push(value.clone());
+                    }
+                }
+            }
+            (Some(json_obj), None) => search_json(
                 &json_obj,
                 self.search_key.clone(),
                 self.search_value.clone(),
@@ -247,9 +248,9 @@ impl Filter {
                 None,
                 false,
                 &mut res
-            );            
-            
-        };
+            ),
+            _ => {}
+        }
         json!({
             "statusCode" : if res.len() > 0 { true } else { false },
             "response": {
@@ -1,22 +1,89 @@
-use super::{Handler, Task, TaskOutput};
+use super::{insert_custom_output, Handler, Task, TaskOutput};
+use crate::client::SendVia;
 use crate::{Event, WorkerInvocation};
 use anyhow::{bail, Result};
 use core::str::FromStr;
 use reqwest::header::{HeaderName, HeaderValue};
 use serde::{Deserialize, Serialize};
-use serde_json::json;
+use serde_json::{json, Value};
+use std::collections::HashMap;
+use tracing::{debug, warn};
 use xpertly_common::*;
 
+/// Loops iterate over the assets attached to the invocation's tag, and any selected directly on the loop task for that
+/// tag, running every inner task once per asset.
+///
+/// Assets are processed in batches of `batch_size` (all assets in a single batch if unset). Batches run one
+/// after another and the loop yields back to the runtime between them so large asset sets don't starve other
+/// invocations sharing the runtime.
+///
+/// If an inner task needs to wait, the loop records a [`LoopCursor`] and the worker is suspended. The cursor,
+/// the asset list fetched in `prepare` and the iteration's outputs are all persisted in the suspension payload as
+/// part of the loop task. On resume the pending output is injected into the waiting inner task's output, then the
+/// loop picks up from the inner task after it, for the same asset, and carries on with the remaining assets.
+/// Assets (and batches) completed before the suspension are not run again.
 #[derive(Serialize, Deserialize, Debug, Clone)]
 #[serde(rename_all = "camelCase")]
 pub struct Loop {
     pub(crate) tasks: Vec<Task>,
     pub(crate) schema: Option<Vec<SchemaItem>>,
     pub(crate) loop_assets: Option<Vec<Object>>,
+    pub(crate) batch_size: Option<usize>,
+    pub(crate) cursor: Option<LoopCursor>,
+    // fail on an asset or device that can't be read, or on a schema item the tag has no objects for, instead of
+    // carrying on with a warning. See `loop_objects` and `check_schema`
+    #[serde(default)]
+    pub(crate) strict_assets: bool,
+    // caps the assets iterated over, see `limit_iterations`
+    #[serde(default)]
+    pub(crate) max_iterations: Option<usize>,
+    #[serde(default)]
+    pub(crate) on_overflow: LoopOverflow,
+}
+
+/// Position within a loop at which execution was suspended
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+#[serde(rename_all = "camelCase")]
+pub struct LoopCursor {
+    // index into `loop_assets` of the asset being processed
+    pub object_index: usize,
+    // index into `tasks` of the next inner task to run for that asset
+    pub task_index: usize,
+    // outputs visible to the iteration when it was suspended
+    pub iteration_outputs: HashMap<String, Value>,
 }
 
 impl Loop {
-    pub async fn prepare(&mut self, context: &WorkerInvocation) -> Result<()> {
+    pub fn is_suspended(&self) -> bool {
+        self.cursor.is_some()
+    }
+
+    /// Injects the output received on resume into the waiting inner task and moves the cursor past it. Fails if the
+    /// cursor doesn't point at one of the loop's tasks, e.g. the suspension payload is stale or has been tampered with
+    pub fn resume(&mut self, pending_output: &Value) -> Result<()> {
+        if let Some(cursor) = self.cursor.as_mut() {
+            let waiting_task = match self.tasks.get(cursor.task_index) {
+                Some(task) => task,
+                None => bail!(
+                    "Loop cursor points at inner task {} but the loop has {} tasks",
+                    cursor.task_index,
+                    self.tasks.len()
+                ),
+            };
+            insert_custom_output(&mut cursor.iteration_outputs, &waiting_task.react_id, pending_output);
+            cursor.task_index += 1;
+        }
+        Ok(())
+    }
+
+    /// Fetches the objects tagged with the invocation's tag to loop over, along with any `selected` directly on the loop
+    /// task for that tag
+    pub async fn prepare(&mut self, context: &WorkerInvocation, selected: &Assets) -> Result<()> {
+        // a suspended loop keeps the assets it was iterating over so the cursor still points at the same asset
+        if self.is_suspended() && self.loop_assets.is_some() {
+            return Ok(());
+        }
+
         let tag = context.tag.as_ref().expect("Loop tasks require a tag");
         let result = context
             .client
@@ -29,97 +96,151 @@ impl Loop {
                 HeaderValue::from_str(&context.auth_token)?,
             )
             .query(&[("tags", tag)])
-            .send()
+            .send_via(context)
             .await?
             .json::<serde_json::Value>()
             .await?;
 
-        let devices = result["devices"][tag].as_array();
-        let assets = result["assets"][tag].as_array();
-        let mut objects = Vec::new();
-
-        if let Some(devices) = devices {
-            devices.iter().for_each(|object| {
-                dbg!(&object);
-                objects.push(serde_json::from_value::<Object>(object.clone()).unwrap())
-            });
+        let objects = with_selected(loop_objects(&result, tag, self.strict_assets)?, tag, selected);
+        if let Some(schema) = &self.schema {
+            check_schema(&objects, schema, tag, self.strict_assets)?;
         }
-
-        if let Some(assets) = assets {
-            assets.iter().for_each(|object| {
-                dbg!(&object);
-                objects.push(serde_json::from_value::<Object>(object.clone()).unwrap())
-            });
-        }
-
-        self.loop_assets = Some(objects);
+        self.loop_assets = Some(limit_iterations(objects, tag, self.max_iterations, self.on_overflow)?);
         Ok(())
     }
 
-    pub async fn execute(&self, context: &WorkerInvocation) -> Result<()> {
-        if let Some(objects) = &self.loop_assets {
-            for object in objects {
-                // create local loop context (probably clone the WorkerInvocation passed to this task)
-                // local loop context wont live beyond this task
-                // should enable inner tasks to reference each other within an iteration
-                let mut loop_context = context.clone();
-                let tag = context.tag.as_ref().expect("Loop tasks require a tag");
-                // this needs to follow the `next` chain, same as in WorkerInvocation.
-                // the two implementations should be merged somehow as the only difference is that this repeats
-                // each contained task for each object in the loop_assets field.
-                // ideally the "Next" object would be smarter and could somehow locate the task that is supposed to run
-                // next and return it. Each task could store the context and the whole system would look more like a linked
-                // list than a worker invocation that contains a list of tasks.
-                for task in self.tasks.iter() {
-                    loop_context
-                        .log(Event::TaskStart, Some(&task), None, None)
-                        .await;
-                    let mut task = task.clone();
-                    task.assets.add_object(tag, object.clone());
-                    task.prepare(&loop_context).await?;
-
-                    let mut task = match task.handler {
-                        // turning off variable subsitiution for loops as inner tasks may not have required variables available yet
-                        // those inner tasks will be rendered when they are executed
-                        Handler::Loop(_) => task,
-                        _ => {
-                            // rendering twice is a workaround for a path parameter translation bug
-                            let task = loop_context.render_variables(&task);
-                            loop_context.render_variables(&task)
-                        }
-                    };
+    pub async fn execute(&mut self, context: &WorkerInvocation) -> Result<()> {
+        let cursor = self.cursor.take();
+        if let Some(objects) = self.loop_assets.clone() {
+            let tag = context.tag.as_ref().expect("Loop tasks require a tag");
+            let batch_size = match self.batch_size {
+                Some(size) if size > 0 => size,
+                _ => objects.len().max(1),
+            };
+            let (start_object, start_task) = match &cursor {
+                Some(cursor) => (cursor.object_index, cursor.task_index),
+                None => (0, 0),
+            };
+
+            for (batch_index, batch) in objects.chunks(batch_size).enumerate() {
+                let batch_start = batch_index * batch_size;
+                // skip batches that were completed before the loop was suspended
+                if batch_start + batch.len() <= start_object {
+                    continue;
+                }
+
+                for (offset, object) in batch.iter().enumerate() {
+                    let object_index = batch_start + offset;
+                    if object_index < start_object {
+                        continue;
+                    }
+                    // a loop can run for a long time over a large fleet, so cancellation is checked every iteration
+                    // and between inner tasks rather than once the loop ends
+                    context.check_cancelled()?;
+
+                    // create local loop context (probably clone the WorkerInvocation passed to this task)
+                    // local loop context wont live beyond this task
+                    // should enable inner tasks to reference each other within an iteration
+                    let mut loop_context = context.clone();
+                    let mut first_task = 0;
+                    if let Some(cursor) = cursor.as_ref().filter(|_| object_index == start_object) {
+                        loop_context
+                            .outputs
+                            .lock()
+                            .extend(cursor.iteration_outputs.clone());
+                        first_task = start_task;
+                    }
 
-                    match task.execute(&loop_context).await {
-                        Ok(task_result) => {
+                    // this needs to follow the `next` chain, same as in WorkerInvocation.
+                    // the two implementations should be merged somehow as the only difference is that this repeats
+                    // each contained task for each object in the loop_assets field.
+                    // ideally the "Next" object would be smarter and could somehow locate the task that is supposed to run
+                    // next and return it. Each task could store the context and the whole system would look more like a linked
+                    // list than a worker invocation that contains a list of tasks.
+                    for (task_index, task) in self.tasks.iter().enumerate().skip(first_task) {
+                        loop_context.check_cancelled()?;
+                        loop_context
+                            .log(Event::TaskStart, Some(&task), None, None)
+                            .await;
+                        // the iteration's object has to be added before `prepare`, which builds the asset vars that
+                        // `{{ASSET:...}}` variables render from. It's added last, so it wins over any configured object
+                        // of the same vendor and type
+                        let mut task = task.clone();
+                        task.assets.add_object(tag, object.clone());
+                        task.prepare(&loop_context).await?;
+
+                        let mut task = match task.handler {
+                            // turning off variable subsitiution for loops as inner tasks may not have required variables available yet
+                            // those inner tasks will be rendered when they are executed
+                            Handler::Loop(_) => task,
+                            _ => {
+                                // rendering twice is a workaround for a path parameter translation bug
+                                let task = loop_context.render_variables(&task)?;
+                                loop_context.render_variables(&task)?
+                            }
+                        };
+
+                        // inner tasks run one after another, so a skipped one just moves on to the next
+                        if !task.should_run()? {
+                            let skipped = Task::skipped_output();
+                            loop_context
+                                .outputs
+                                .lock()
+                                .insert(task.react_id.clone(), skipped.clone());
                             loop_context
-                                .log(
+                                .log_with_reason(
                                     Event::TaskSuccess,
                                     Some(&task),
-                                    Some(task_result.clone()),
-                                    None,
+                                    Some(task.output_from_value(skipped)),
+                                    Some(String::from("Skipped, runIf isn't met")),
                                 )
                                 .await;
-                            match task_result {
-                                TaskOutput::EndpointResult(result)
-                                | TaskOutput::WebhookResult(result)
-                                | TaskOutput::ConditionalResult(result) => {
-                                    loop_context
-                                        .outputs
-                                        .lock()
-                                        .unwrap()
-                                        .insert(task.react_id.clone(), json!(result));
+                            continue;
+                        }
+
+                        match task.execute(&loop_context).await {
+                            Ok(task_result) => {
+                                loop_context
+                                    .log(
+                                        Event::TaskSuccess,
+                                        Some(&task),
+                                        Some(task_result.clone()),
+                                        None,
+                                    )
+                                    .await;
+                                match task_result {
+                                    TaskOutput::EndpointResult(result)
+                                    | TaskOutput::WebhookResult(result)
+                                    | TaskOutput::ConditionalResult(result) => {
+                                        loop_context
+                                            .outputs
+                                            .lock()
+                                            .insert(task.react_id.clone(), json!(result));
+                                    }
+                                    _ => {}
+                                }
+
+                                if task.needs_to_wait {
+                                    self.cursor = Some(LoopCursor {
+                                        object_index,
+                                        task_index,
+                                        iteration_outputs: loop_context.outputs.lock().clone(),
+                                    });
+                                    return Ok(());
                                 }
-                                _ => {}
                             }
-                        }
-                        Err(e) => {
-                            loop_context
-                                .log(Event::TaskFail, Some(&task), None, Some(e))
-                                .await;
-                            bail!("Loop task failed because an inner task failed");
+                            Err(e) => {
+                                loop_context
+                                    .log(Event::TaskFail, Some(&task), None, Some(e))
+                                    .await;
+                                bail!("Loop task failed because an inner task failed");
+                            }
                         }
                     }
                 }
+
+                debug!(batch_index, "loop batch complete");
+                tokio::task::yield_now().await;
             }
 
             // potentially produce some useful outputs from the loop task and save them to the global worker context,
@@ -129,3 +250,109 @@ impl Loop {
         Ok(())
     }
 }
+
+// the union of the objects fetched for `tag` and those selected for it, devices then assets. An object that's both
+// tagged and selected is only looped over once, see `Assets::merge`
+pub(crate) fn with_selected(fetched: Vec<Object>, tag: &str, selected: &Assets) -> Vec<Object> {
+    let mut union = Assets {
+        schema: None,
+        objects: None,
+    };
+    for object in fetched {
+        union.add_object(tag, object);
+    }
+    union.merge(selected.clone());
+    match union.objects.and_then(|mut objects| objects.remove(tag)) {
+        Some(objects) => objects
+            .devices
+            .unwrap_or_default()
+            .into_iter()
+            .map(Object::Device)
+            .chain(objects.assets.unwrap_or_default().into_iter().map(Object::Asset))
+            .collect(),
+        None => Vec::new(),
+    }
+}
+
+// the vendor and asset type of a loop object, devices take their type from their `deviceType` attribute
+fn object_type(object: &Object) -> (&str, Option<&str>) {
+    match object {
+        Object::Asset(asset) => (asset.integration_type.as_str(), Some(asset.asset_type.as_str())),
+        Object::Device(device) => (device.integration_type.as_str(), device.attributes["deviceType"].as_str()),
+    }
+}
+
+// every vendor and asset type in a loop's schema has to be among the objects it loops over, otherwise inner tasks
+// referencing them would run against missing data. Missing types fail the loop when `strict`, and are logged as a
+// warning otherwise
+pub(crate) fn check_schema(objects: &[Object], schema: &[SchemaItem], tag: &str, strict: bool) -> Result<()> {
+    let missing = schema
+        .iter()
+        .filter(|item| {
+            !objects.iter().any(|object| {
+                let (vendor, asset_type) = object_type(object);
+                vendor == item.vendor && asset_type == Some(item.asset_type.as_str())
+            })
+        })
+        .map(|item| format!("{}.{}", item.vendor, item.asset_type))
+        .collect::<Vec<String>>();
+    if missing.is_empty() {
+        return Ok(());
+    }
+    if strict {
+        bail!("Tag {} has no objects for the loop's schema: {}", tag, missing.join(", "));
+    }
+    warn!(tag, missing = %missing.join(", "), "loop schema items missing from tag");
+    Ok(())
+}
+
+// guards against accidentally looping over a tag covering an entire inventory. Loops over more than `max_iterations`
+// objects are capped to the first `max_iterations` with a warning, or fail, depending on `on_overflow`
+pub(crate) fn limit_iterations(
+    mut objects: Vec<Object>,
+    tag: &str,
+    max_iterations: Option<usize>,
+    on_overflow: LoopOverflow,
+) -> Result<Vec<Object>> {
+    let max_iterations = match max_iterations {
+        Some(max_iterations) if objects.len() > max_iterations => max_iterations,
+        _ => return Ok(objects),
+    };
+    match on_overflow {
+        LoopOverflow::Cap => {
+            warn!(tag, found = objects.len(), max_iterations, "loop capped at its maximum iterations");
+            objects.truncate(max_iterations);
+            Ok(objects)
+        }
+        LoopOverflow::Fail => bail!(
+            "Tag {} has {} objects to loop over, more than the loop's maximum of {}",
+            tag,
+            objects.len(),
+            max_iterations
+        ),
+    }
+}
+
+// the devices then assets tagged with `tag` in an assets-by-tags response. Objects that can't be read are skipped
+// with a warning, or fail the loop when `strict`
+pub(crate) fn loop_objects(result: &Value, tag: &str, strict: bool) -> Result<Vec<Object>> {
+    let mut objects = Vec::new();
+    let mut skipped = 0;
+    for kind in ["devices", "assets"] {
+        for (index, object) in result[kind][tag].as_array().into_iter().flatten().enumerate() {
+            debug!(?object, "loop object");
+            match serde_json::from_value::<Object>(object.clone()) {
+                Ok(object) => objects.push(object),
+                Err(err) if strict => bail!("Invalid {} at index {} for tag {}: {}", kind, index, tag, err),
+                Err(err) => {
+                    warn!(kind, index, tag, %err, "skipping loop object that couldn't be read");
+                    skipped += 1;
+                }
+            }
+        }
+    }
+    if skipped > 0 {
+        warn!(skipped, tag, "loop skipped objects that couldn't be read");
+    }
+    Ok(objects)
+}
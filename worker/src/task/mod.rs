@@ -2,31 +2,49 @@ pub mod conditional;
 pub mod endpoint;
 pub mod looping;
 pub mod filter;
+pub mod switch;
 
 use std::{collections::HashMap, fmt::{Display, Formatter}};
 
 pub use conditional::Conditional;
 pub use endpoint::Endpoint;
-pub use looping::Loop;
+pub use looping::{Loop, LoopCursor};
 pub use filter::Filter;
+pub use switch::Switch;
 
 use xpertly_common::*;
 use anyhow::{bail, Result};
 use async_recursion::async_recursion;
 use serde_json::{ json, Value };
 use serde::{Deserialize, Serialize};
+use tracing::debug;
 
 // use crate::asset::Assets;
 use crate::WorkerInvocation;
 
+// camelCase like the task config, invocations suspended while tasks were saved in snake_case still resume through
+// the aliases
 #[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(rename_all = "camelCase")]
 pub struct Task {
     pub name: String,
+    #[serde(alias = "react_id")]
     pub react_id: String,
     pub next: Option<Next>,
     pub assets: Assets,
+    #[serde(alias = "asset_vars")]
     pub asset_vars: Option<HashMap<String, HashMap<String, Value>>>,
+    #[serde(alias = "needs_to_wait")]
     pub needs_to_wait: bool,
+    // see `Task::should_run`
+    #[serde(default, alias = "run_if")]
+    pub run_if: Option<Vec<ConditionGroup>>,
+    // see `WorkerInvocation::validate_custom_output`
+    #[serde(default)]
+    pub output_schema: Option<OutputSchema>,
+    // see `TaskConfig::output_alias`
+    #[serde(default)]
+    pub output_alias: Option<String>,
     pub handler: Handler
 }
 
@@ -37,7 +55,95 @@ pub enum TaskOutput {
     LoopResult(bool),
     EndpointResult(serde_json::Value),
     WebhookResult(serde_json::Value),
-    FilterResult(serde_json::Value)
+    FilterResult(serde_json::Value),
+    SwitchResult(serde_json::Value),
+}
+
+impl TaskOutput {
+    pub fn into_value(self) -> Value {
+        match self {
+            TaskOutput::ConditionalResult(result)
+            | TaskOutput::EndpointResult(result)
+            | TaskOutput::WebhookResult(result)
+            | TaskOutput::FilterResult(result)
+            | TaskOutput::SwitchResult(result) => result,
+            TaskOutput::LoopResult(result) => json!(result),
+        }
+    }
+
+    // the output with every occurrence of the secrets masked, see `secrets`
+    pub fn masked(self, secrets: &[String]) -> TaskOutput {
+        let mask = |mut value: Value| {
+            crate::secrets::mask(&mut value, secrets);
+            value
+        };
+        match self {
+            TaskOutput::ConditionalResult(result) => TaskOutput::ConditionalResult(mask(result)),
+            TaskOutput::LoopResult(result) => TaskOutput::LoopResult(result),
+            TaskOutput::EndpointResult(result) => TaskOutput::EndpointResult(mask(result)),
+            TaskOutput::WebhookResult(result) => TaskOutput::WebhookResult(mask(result)),
+            TaskOutput::FilterResult(result) => TaskOutput::FilterResult(mask(result)),
+            TaskOutput::SwitchResult(result) => TaskOutput::SwitchResult(mask(result)),
+        }
+    }
+
+    /// A short summary of the output for logs, e.g. `200 OK, 12 items` for an endpoint that responded with an array.
+    /// Loops have nothing to summarize
+    pub fn summary(&self) -> Option<String> {
+        match self {
+            TaskOutput::EndpointResult(result) | TaskOutput::WebhookResult(result) => {
+                let code = result["statusCode"].as_u64()?;
+                let status = match reqwest::StatusCode::from_u16(code as u16).ok().and_then(|s| s.canonical_reason()) {
+                    Some(reason) => format!("{} {}", code, reason),
+                    None => code.to_string(),
+                };
+                match result["response"].as_array() {
+                    Some(items) => Some(format!("{}, {}", status, count(items.len(), "item"))),
+                    None => Some(status),
+                }
+            }
+            TaskOutput::ConditionalResult(result) => result["statusCode"].as_bool().map(|result| result.to_string()),
+            TaskOutput::FilterResult(result) => {
+                result["response"]["count"].as_u64().map(|matches| count(matches as usize, "match"))
+            }
+            TaskOutput::SwitchResult(result) => Some(format!("value {}", result["value"])),
+            TaskOutput::LoopResult(_) => None,
+        }
+    }
+}
+
+fn count(n: usize, noun: &str) -> String {
+    match (n, noun.ends_with("ch")) {
+        (1, _) => format!("1 {}", noun),
+        (_, true) => format!("{} {}es", n, noun),
+        (_, false) => format!("{} {}s", n, noun),
+    }
+}
+
+/// A task output tagged with the kind of task that produced it, e.g. `{"type": "conditional", "result": {...}}`.
+/// `TaskOutput` is untagged, so a conditional result and an endpoint result can serialize to the same shape.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(tag = "type", content = "result", rename_all = "camelCase")]
+pub enum LoggedOutput {
+    Conditional(Value),
+    Loop(bool),
+    Endpoint(Value),
+    Webhook(Value),
+    Filter(Value),
+    Switch(Value),
+}
+
+impl From<TaskOutput> for LoggedOutput {
+    fn from(output: TaskOutput) -> Self {
+        match output {
+            TaskOutput::ConditionalResult(result) => LoggedOutput::Conditional(result),
+            TaskOutput::LoopResult(result) => LoggedOutput::Loop(result),
+            TaskOutput::EndpointResult(result) => LoggedOutput::Endpoint(result),
+            TaskOutput::WebhookResult(result) => LoggedOutput::Webhook(result),
+            TaskOutput::FilterResult(result) => LoggedOutput::Filter(result),
+            TaskOutput::SwitchResult(result) => LoggedOutput::Switch(result),
+        }
+    }
 }
 
 impl Task {
@@ -45,7 +151,14 @@ impl Task {
         // build asset variable structure
         let mut asset_vars = HashMap::new();
         if let Some(ref assets) = self.assets.objects {
-            let tag_assets = assets.get(context.tag.clone().unwrap().as_str()).unwrap();
+            let tag_assets = match context.tag.as_deref().and_then(|tag| assets.get(tag)) {
+                Some(tag_assets) => tag_assets,
+                None => bail!(
+                    "Task {} has assets for other tags but none for tag {}",
+                    self.name,
+                    context.tag.as_deref().unwrap_or("None")
+                ),
+            };
             if let Some(ref assets) = tag_assets.assets {
                 for asset in assets.iter() {
                     let vendor = asset.integration_type.clone();
@@ -83,12 +196,15 @@ impl Task {
                 endpoint_task.prepare(context).await?;
             },
             Handler::Loop(loop_task) => {
-                loop_task.prepare(context).await?;
+                loop_task.prepare(context, &self.assets).await?;
             },
             Handler::Filter(filter_test) => {
                 filter_test.prepare(context).await?;
             },
-            _ => {}
+            // webhooks send to a URL of their own and don't authenticate with an integration, so none is fetched
+            Handler::Webhook(_) => {}
+            Handler::Conditional(_) => {}
+            Handler::Switch(_) => {}
         }
         Ok(())
     }
@@ -102,7 +218,6 @@ impl Task {
                         context
                             .outputs
                             .lock()
-                            .unwrap()
                             .insert(self.react_id.clone(), result["response"].clone());
                         Ok(TaskOutput::EndpointResult(result.clone()))
                     }
@@ -112,12 +227,12 @@ impl Task {
                 }
             },
             Handler::Webhook(endpoint_task) => {
+                endpoint_task.strip_webhook_headers();
                 match endpoint_task.execute(context).await {
                     Ok(result) => {
                         context
                             .outputs
                             .lock()
-                            .unwrap()
                             .insert(self.react_id.clone(), result.clone());
                         Ok(TaskOutput::WebhookResult(json!({ "statusCode": 200, "response": "Webhook sent" })))
                     }
@@ -131,7 +246,6 @@ impl Task {
                     context
                         .outputs
                         .lock()
-                        .unwrap()
                         .insert(self.react_id.clone(), result["statusCode"].clone());
                     Ok(TaskOutput::ConditionalResult(result))
                 }
@@ -145,7 +259,6 @@ impl Task {
                         context
                             .outputs
                             .lock()
-                            .unwrap()
                             .insert(self.react_id.clone(), json!(result));
                         Ok(TaskOutput::LoopResult(true))
                     }
@@ -158,20 +271,69 @@ impl Task {
                 let ret = filter_task.execute(context).await;
 
                 if let Some(result) = ret.get("response") {
-                    dbg!(&result);
+                    debug!(?result, "filter result");
                     context
                         .outputs
                         .lock()
-                        .unwrap()
                         .insert(self.react_id.clone(), result.clone());
                 }
                 
                 Ok(TaskOutput::FilterResult(ret))
             }
+            Handler::Switch(switch_task) => {
+                let result = switch_task.execute();
+                context
+                    .outputs
+                    .lock()
+                    .insert(self.react_id.clone(), result["value"].clone());
+                Ok(TaskOutput::SwitchResult(result))
+            }
+        }
+    }
+
+    // rebuilds this task's output from a result stored with `TaskOutput::into_value`. The output enum is untagged so
+    // the task's handler is needed to tell which variant the result belongs to
+    pub fn output_from_value(&self, result: Value) -> TaskOutput {
+        match self.handler {
+            Handler::Endpoint(_) => TaskOutput::EndpointResult(result),
+            Handler::Webhook(_) => TaskOutput::WebhookResult(result),
+            Handler::Conditional(_) => TaskOutput::ConditionalResult(result),
+            Handler::Loop(_) => TaskOutput::LoopResult(result.as_bool().unwrap_or(true)),
+            Handler::Filter(_) => TaskOutput::FilterResult(result),
+            Handler::Switch(_) => TaskOutput::SwitchResult(result),
+        }
+    }
+
+    // tasks without a runIf always run. It's evaluated like a conditional task's expression, so it has to be
+    // rendered first
+    pub fn should_run(&self) -> Result<bool> {
+        match &self.run_if {
+            Some(expression) => Conditional { expression: expression.clone() }.eval(),
+            None => Ok(true),
+        }
+    }
+
+    // the name {{OUTPUT:...}} variables reference the task's output by, its output alias if it has one
+    pub fn output_name(&self) -> &str {
+        self.output_alias.as_deref().filter(|alias| !alias.is_empty()).unwrap_or(&self.name)
+    }
+
+    // the output of a task skipped because of its runIf
+    pub fn skipped_output() -> Value {
+        json!({ "skipped": true })
+    }
+
+    // whether execution stopped part-way through this task and the worker needs to be suspended,
+    // e.g. a loop with an inner task that is waiting on an external system
+    pub fn is_suspended(&self) -> bool {
+        match &self.handler {
+            Handler::Loop(loop_task) => loop_task.is_suspended(),
+            _ => false,
         }
     }
 
     pub fn from_config(task_config: TaskConfig) -> Result<Task> {
+        check_fields(&task_config)?;
         // seems redundant but the data structure needs to be altered slightly before execution
         let handler = match task_config.fields {
             TaskFields::Endpoint(endpoint_fields) => {
@@ -190,6 +352,14 @@ impl Task {
                     None
                 };
 
+                let multipart = match endpoint_fields.body_type {
+                    BodyType::Json => None,
+                    BodyType::Multipart => match endpoint_fields.parts {
+                        Some(parts) if !parts.is_empty() => Some(parts),
+                        _ => bail!("Multipart endpoint task {} has no parts", task_config.react_id),
+                    },
+                };
+
                 let endpoint_task = Endpoint {
                     vendor: task_config.vendor.unwrap_or("".to_string()),
                     integration_id: task_config.integration_id,
@@ -198,8 +368,11 @@ impl Task {
                     headers: endpoint_fields.headers,
                     path_params: endpoint_fields.path_params,
                     query_params,
+                    send_empty_query_params: endpoint_fields.send_empty_query_params,
                     body: endpoint_fields.body,
+                    multipart,
                     target_url: endpoint_fields.target_url,
+                    response_path: endpoint_fields.response_path,
                 };
 
                 if let Some(category) = task_config.category {
@@ -222,11 +395,16 @@ impl Task {
             }
             TaskFields::Loop(loop_config) => {
                 // convert all tasks in the loop task config to task objects
-                let tasks = loop_config.tasks.into_iter().map(|task_config| Task::from_config(task_config).unwrap()).collect();
+                let tasks = loop_config.tasks.into_iter().map(Task::from_config).collect::<Result<Vec<Task>>>()?;
                 Handler::Loop(Loop {
                     tasks,
                     schema: task_config.assets.schema.clone(),
-                    loop_assets: None
+                    loop_assets: None,
+                    batch_size: loop_config.batch_size,
+                    cursor: None,
+                    strict_assets: loop_config.strict_assets,
+                    max_iterations: loop_config.max_iterations,
+                    on_overflow: loop_config.on_overflow,
                 })
             }
             TaskFields::Filter(filter_fields) => {
@@ -235,9 +413,13 @@ impl Task {
                     condition: filter_fields.condition,
                     search_key: filter_fields.search_key,
                     search_value: filter_fields.search_value,
+                    pointer: filter_fields.pointer,
                     json_obj: None
                 })
             }
+            TaskFields::Switch(switch_fields) => Handler::Switch(Switch {
+                value: switch_fields.value,
+            }),
         };
 
         Ok(Task { 
@@ -247,11 +429,62 @@ impl Task {
             assets: task_config.assets,
             asset_vars: None,
             needs_to_wait: task_config.needs_to_wait,
+            run_if: task_config.run_if,
+            output_schema: task_config.output_schema,
+            output_alias: task_config.output_alias,
             handler 
         })
     }
 }
 
+// The fields are untagged, so they're deserialized as whichever kind of task's they fit first, which isn't necessarily
+// the task's declared type. Tasks whose fields don't match their type are rejected rather than run as another kind of
+// task. Types we don't know of are left alone, endpoint tasks have always accepted any type but `webhook`
+fn check_fields(task_config: &TaskConfig) -> Result<()> {
+    let expected = match task_config.category.as_deref() {
+        Some("endpoint") | Some("webhook") => "endpoint",
+        Some(task_type @ ("loop" | "conditional" | "filter" | "switch")) => task_type,
+        _ => return Ok(()),
+    };
+    let actual = task_config.fields.task_type();
+    if actual != expected {
+        bail!(
+            "Task {} is a {} task but its fields are for a {} task",
+            task_config.name.as_deref().unwrap_or(&task_config.react_id),
+            task_config.category.as_deref().unwrap_or_default(),
+            actual
+        );
+    }
+    Ok(())
+}
+
+// the key a custom output is stored under when the task's own output isn't an object, next to that output
+pub fn custom_output_key(react_id: &str) -> String {
+    format!("{}.customOutput", react_id)
+}
+
+// Records the output a suspended task is resumed with and returns the task's output. Object outputs take it under
+// `customOutput`. Other outputs (e.g. the array a list endpoint responds with) keep their shape and it's stored under
+// `custom_output_key` instead. Either way it's referenced as {{OUTPUT:task.customOutput.field}}
+pub fn insert_custom_output(outputs: &mut HashMap<String, Value>, react_id: &str, custom_output: &Value) -> Value {
+    match outputs.get_mut(react_id) {
+        Some(Value::Object(object)) => {
+            object.insert("customOutput".to_string(), custom_output.clone());
+            Value::Object(object.clone())
+        }
+        Some(output) => {
+            let output = output.clone();
+            outputs.insert(custom_output_key(react_id), custom_output.clone());
+            output
+        }
+        None => {
+            let output = json!({ "customOutput": custom_output });
+            outputs.insert(react_id.to_string(), output.clone());
+            output
+        }
+    }
+}
+
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub enum Handler {
     Endpoint(Endpoint),
@@ -259,6 +492,7 @@ pub enum Handler {
     Loop(Loop),
     Webhook(Endpoint),
     Filter(Filter),
+    Switch(Switch),
 }
 
 impl Handler {
@@ -276,6 +510,11 @@ impl Handler {
     }
 }
 
+impl Handler {
+    /// Task types as they're displayed, one per variant
+    pub const TYPES: [&'static str; 6] = ["endpoint", "conditional", "loop", "webhook", "filter", "switch"];
+}
+
 impl Display for Handler {
     fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
         match self {
@@ -294,6 +533,9 @@ impl Display for Handler {
             Handler::Filter(_) => {
                 write!(f, "filter")
             }
+            Handler::Switch(_) => {
+                write!(f, "switch")
+            }
         }
     }
 }
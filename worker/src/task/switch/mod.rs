@@ -0,0 +1,15 @@
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+
+/// Branches on a rendered value, following the `Next` case keyed by it or the default branch when no case matches
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct Switch {
+    pub(crate) value: String,
+}
+
+impl Switch {
+    pub fn execute(&self) -> Value {
+        json!({ "value": self.value })
+    }
+}
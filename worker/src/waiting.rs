@@ -0,0 +1,245 @@
+// Invocations waiting for a callback. The callback may never arrive, so a sweeper periodically looks for runs that
+// have waited past their deadline and either fails them or raises an alert. Runs are tracked as this process suspends
+// them, and the sweeper starts by seeding the runs left waiting in the suspended payload store, e.g. before a restart.
+use crate::{client, runs, InvocationState, Publish, WorkerInvocation};
+use actix::Recipient;
+use anyhow::Result;
+use chrono::{DateTime, TimeZone, Utc};
+use jsonwebtoken::{DecodingKey, Validation};
+use once_cell::sync::Lazy;
+use parking_lot::Mutex;
+use reqwest::header::{HeaderName, HeaderValue, AUTHORIZATION};
+use serde_json::json;
+use std::collections::HashMap;
+use std::str::FromStr;
+use std::time::Duration;
+use tracing::{debug, warn};
+use uuid::Uuid;
+
+// how often the sweeper checks for runs that have waited too long
+const SWEEP_INTERVAL: Duration = Duration::from_secs(60);
+
+// longest a run may wait for its callback. Without it a run waits until its wait token expires
+static MAX_WAIT: Lazy<Option<chrono::Duration>> = Lazy::new(|| {
+    std::env::var("WAIT_TIMEOUT_SECS")
+        .ok()
+        .and_then(|secs| secs.parse::<i64>().ok())
+        .filter(|secs| *secs > 0)
+        .map(chrono::Duration::seconds)
+});
+
+// what the sweeper does with runs past their deadline, set with WAIT_TIMEOUT_ACTION=fail|alert
+static TIMEOUT_ACTION: Lazy<WaitTimeoutAction> = Lazy::new(|| {
+    match std::env::var("WAIT_TIMEOUT_ACTION").as_deref() {
+        Ok("fail") => WaitTimeoutAction::Fail,
+        _ => WaitTimeoutAction::Alert,
+    }
+});
+
+static WAITING_RUNS: Lazy<Mutex<HashMap<Uuid, WaitingRun>>> = Lazy::new(|| Mutex::new(HashMap::new()));
+
+// most waiting runs `seed` reads from the payload store, the largest page Elasticsearch returns by default
+const SEED_LIMIT: usize = 10_000;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum WaitTimeoutAction {
+    /// fails the run with a `wait timeout` log, its finalizer runs as for any other failure
+    Fail,
+    /// publishes a `wait_timeout` event for the run once and leaves it waiting
+    Alert,
+}
+
+#[derive(Debug, Clone)]
+pub(crate) struct WaitingRun {
+    // the wait token's `auth` claim, used to fetch the suspended payload
+    auth_token: String,
+    suspended_at: DateTime<Utc>,
+    pub(crate) deadline: DateTime<Utc>,
+    alerted: bool,
+}
+
+// records a run that was just suspended, replacing any earlier suspension of the same run
+pub(crate) fn track(run_id: Uuid, auth_token: &str, wait_token: &str) {
+    let suspended_at = Utc::now();
+    let run = WaitingRun {
+        auth_token: auth_token.to_string(),
+        suspended_at,
+        deadline: deadline(suspended_at, wait_token, *MAX_WAIT),
+        alerted: false,
+    };
+    WAITING_RUNS.lock().insert(run_id, run);
+}
+
+pub(crate) fn untrack(run_id: Uuid) {
+    WAITING_RUNS.lock().remove(&run_id);
+}
+
+// the wait token's expiry, brought forward to `max_wait` after suspending if that's sooner. Tokens without a
+// readable expiry get the same 24 hours `construct_wait_token` gives them by default
+pub(crate) fn deadline(
+    suspended_at: DateTime<Utc>,
+    wait_token: &str,
+    max_wait: Option<chrono::Duration>,
+) -> DateTime<Utc> {
+    let expiry = wait_token_expiry(wait_token).unwrap_or(suspended_at + chrono::Duration::hours(24));
+    match max_wait {
+        Some(max_wait) => expiry.min(suspended_at + max_wait),
+        None => expiry,
+    }
+}
+
+/// Tracks the runs left waiting in the suspended payload store, read from the Elasticsearch cluster at
+/// ELASTICSEARCH_URL. Runs already tracked keep their deadline. Returns the number of runs added
+pub async fn seed() -> Result<usize> {
+    let url = match std::env::var("ELASTICSEARCH_URL") {
+        Ok(url) => url,
+        Err(_) => anyhow::bail!("ELASTICSEARCH_URL isn't set, waiting runs can't be read"),
+    };
+    let mut request = client::build_client()
+        .post(format!("{}/xpertly_handler_payload_*/_search", url.trim_end_matches('/')))
+        .json(&json!({
+            "size": SEED_LIMIT,
+            "query": { "match": { "state": "waiting" } },
+            "_source": ["runId", "authToken", "@timestamp"],
+        }));
+    if let Ok(api_key) = std::env::var("ELASTICSEARCH_API_KEY") {
+        request = request.header(AUTHORIZATION, format!("ApiKey {}", api_key));
+    }
+    let response = request
+        .send()
+        .await?
+        .error_for_status()?
+        .json::<serde_json::Value>()
+        .await?;
+
+    let mut waiting = WAITING_RUNS.lock();
+    let mut seeded = 0;
+    let hits = response["hits"]["hits"].as_array().map(Vec::as_slice).unwrap_or_default();
+    for (run_id, run) in hits.iter().filter_map(|hit| seeded_run(&hit["_source"])) {
+        if !waiting.contains_key(&run_id) {
+            waiting.insert(run_id, run);
+            seeded += 1;
+        }
+    }
+    Ok(seeded)
+}
+
+// the waiting run a suspended payload is for. The wait token isn't saved with it, so the deadline is the default
+// wait token lifetime after it was suspended
+pub(crate) fn seeded_run(payload: &serde_json::Value) -> Option<(Uuid, WaitingRun)> {
+    let run_id = payload["runId"].as_str()?.parse::<Uuid>().ok()?;
+    let auth_token = payload["authToken"].as_str()?.to_string();
+    let suspended_at = DateTime::parse_from_rfc3339(payload["@timestamp"].as_str()?)
+        .ok()?
+        .with_timezone(&Utc);
+    let run = WaitingRun {
+        auth_token,
+        suspended_at,
+        deadline: deadline(suspended_at, "", *MAX_WAIT),
+        alerted: false,
+    };
+    Some((run_id, run))
+}
+
+fn wait_token_expiry(wait_token: &str) -> Option<DateTime<Utc>> {
+    let key = DecodingKey::from_secret("wow much secret".as_ref());
+    let mut validation = Validation::default();
+    // an expired token still has the deadline we're after
+    validation.validate_exp = false;
+    let token = jsonwebtoken::decode::<serde_json::Value>(wait_token, &key, &validation).ok()?;
+    let exp = token.claims["exp"].as_i64()?;
+    Utc.timestamp_opt(exp, 0).single()
+}
+
+/// Number of runs suspended by this process that are waiting for a callback
+pub fn waiting_runs() -> usize {
+    WAITING_RUNS.lock().len()
+}
+
+/// Number of waiting runs that are past their deadline and haven't been failed yet
+pub fn stuck_waiting_runs() -> usize {
+    let now = Utc::now();
+    WAITING_RUNS
+        .lock()
+        .values()
+        .filter(|run| run.deadline <= now)
+        .count()
+}
+
+/// Fails or raises an alert for every waiting run past its deadline. Runs that have been resumed or cancelled
+/// elsewhere in the meantime are dropped without touching them
+pub async fn sweep(action: WaitTimeoutAction, channel: Option<Recipient<Publish>>) {
+    let now = Utc::now();
+    let overdue = WAITING_RUNS
+        .lock()
+        .iter()
+        .filter(|(_, run)| run.deadline <= now && !run.alerted)
+        .map(|(run_id, run)| (*run_id, run.clone()))
+        .collect::<Vec<(Uuid, WaitingRun)>>();
+
+    for (run_id, run) in overdue {
+        let invocation = match fetch_suspended(run_id, &run.auth_token).await {
+            Ok(invocation) => invocation,
+            Err(err) => {
+                debug!(%run_id, ?err, "failed to fetch suspended run, retrying next sweep");
+                continue;
+            }
+        };
+        if invocation.state() != InvocationState::Waiting {
+            untrack(run_id);
+            continue;
+        }
+        let waited = (now - run.suspended_at).to_std().unwrap_or_default();
+        match action {
+            // a callback may be resuming the run at the same time, whichever claims it first goes ahead
+            WaitTimeoutAction::Fail => match runs::claim(run_id).await {
+                Ok(true) => {
+                    untrack(run_id);
+                    invocation.time_out(waited, channel.clone()).await;
+                }
+                Ok(false) => untrack(run_id),
+                Err(err) => debug!(%run_id, ?err, "failed to claim run, retrying next sweep"),
+            },
+            WaitTimeoutAction::Alert => {
+                if let Some(run) = WAITING_RUNS.lock().get_mut(&run_id) {
+                    run.alerted = true;
+                }
+                invocation.alert_wait_timeout(waited, channel.clone()).await;
+            }
+        }
+    }
+}
+
+/// Sweeps on the shared worker runtime every minute with the action set by WAIT_TIMEOUT_ACTION, publishing logs to
+/// `channel`. The runs left waiting in the payload store are seeded first, see `seed`
+pub fn sweep_periodically(channel: Option<Recipient<Publish>>) {
+    crate::runtime().spawn(async move {
+        match seed().await {
+            Ok(seeded) => debug!(seeded, "seeded waiting runs"),
+            Err(err) => warn!(?err, "failed to seed waiting runs, only runs suspended from now on are swept"),
+        }
+        let mut interval = tokio::time::interval(SWEEP_INTERVAL);
+        loop {
+            interval.tick().await;
+            sweep(*TIMEOUT_ACTION, channel.clone()).await;
+        }
+    });
+}
+
+async fn fetch_suspended(run_id: Uuid, auth_token: &str) -> Result<WorkerInvocation> {
+    let suspended_invocation = client::build_client()
+        .get(format!(
+            "https://api.dev.xpertly.io/v1/client/get_handler_payload/{}",
+            run_id
+        ))
+        .header(
+            HeaderName::from_str("Authorization")?,
+            HeaderValue::from_str(auth_token)?,
+        )
+        .send()
+        .await?
+        .error_for_status()?
+        .json::<serde_json::Value>()
+        .await?;
+    WorkerInvocation::from_suspended(suspended_invocation)
+}